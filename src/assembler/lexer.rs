@@ -0,0 +1,256 @@
+use super::{AsmError, AsmErrorKind};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    Ident(String),
+    Int(i128),
+    Float(f64),
+    Str(Vec<u8>),
+    Colon,
+    Comma,
+}
+
+// token with its 1-based column
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Spanned {
+    pub(crate) token: Token,
+    pub(crate) column: usize,
+}
+
+// split one source line into tokens, stops at `;` comments
+pub(crate) fn tokenize(line_no: usize, line: &str) -> Result<Vec<Spanned>, AsmError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        let err = |kind| AsmError {
+            line: line_no,
+            column,
+            kind,
+        };
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+        let token = match c {
+            ':' => {
+                i += 1;
+                Token::Colon
+            }
+            ',' => {
+                i += 1;
+                Token::Comma
+            }
+            '\'' => {
+                let (bytes, end) = lex_quoted(&chars, i, '\'').map_err(err)?;
+                let text: String = chars[i..end].iter().collect();
+                i = end;
+                match bytes[..] {
+                    [b] => Token::Int(b as i128),
+                    _ => return Err(err(AsmErrorKind::InvalidLiteral(text))),
+                }
+            }
+            '"' => {
+                let (bytes, end) = lex_quoted(&chars, i, '"').map_err(err)?;
+                i = end;
+                Token::Str(bytes)
+            }
+            _ if c.is_ascii_digit()
+                || ((c == '-' || c == '+')
+                    && chars.get(i + 1).is_some_and(|d| d.is_ascii_digit())) =>
+            {
+                let start = i;
+                i += 1;
+                while i < chars.len() {
+                    let d = chars[i];
+                    let exp_sign = (d == '-' || d == '+')
+                        && matches!(chars[i - 1], 'e' | 'E')
+                        && !is_hex(&chars[start..i]);
+                    if d.is_ascii_alphanumeric() || d == '_' || d == '.' || exp_sign {
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let text: String = chars[start..i].iter().collect();
+                parse_number(&text).ok_or(err(AsmErrorKind::InvalidLiteral(text)))?
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                Token::Ident(chars[start..i].iter().collect())
+            }
+            _ => return Err(err(AsmErrorKind::UnexpectedChar(c))),
+        };
+        tokens.push(Spanned { token, column });
+    }
+    Ok(tokens)
+}
+
+fn is_hex(text: &[char]) -> bool {
+    let digits = match text.first() {
+        Some('-' | '+') => &text[1..],
+        _ => text,
+    };
+    matches!(digits, ['0', 'x' | 'X', ..])
+}
+
+// lex a quoted literal starting at `start`, returns bytes and the index after the closing quote
+fn lex_quoted(chars: &[char], start: usize, quote: char) -> Result<(Vec<u8>, usize), AsmErrorKind> {
+    let mut bytes = Vec::new();
+    let mut i = start + 1;
+    loop {
+        let c = *chars.get(i).ok_or(AsmErrorKind::UnterminatedLiteral)?;
+        i += 1;
+        if c == quote {
+            return Ok((bytes, i));
+        }
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let e = *chars.get(i).ok_or(AsmErrorKind::UnterminatedLiteral)?;
+        i += 1;
+        let b = match e {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            '0' => 0,
+            '\\' => b'\\',
+            '\'' => b'\'',
+            '"' => b'"',
+            'x' => {
+                let hex: String = chars.get(i..i + 2).unwrap_or_default().iter().collect();
+                i += 2;
+                u8::from_str_radix(&hex, 16)
+                    .map_err(|_| AsmErrorKind::InvalidEscape(format!("\\x{hex}")))?
+            }
+            _ => return Err(AsmErrorKind::InvalidEscape(format!("\\{e}"))),
+        };
+        bytes.push(b);
+    }
+}
+
+fn parse_number(text: &str) -> Option<Token> {
+    let (negative, digits) = match text.as_bytes()[0] {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let digits = digits.replace('_', "");
+    let (radix, body) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        Some("0o" | "0O") => (8, &digits[2..]),
+        _ => (10, &digits[..]),
+    };
+    if let Ok(v) = u64::from_str_radix(body, radix) {
+        let v = v as i128;
+        return Some(Token::Int(if negative { -v } else { v }));
+    }
+    if radix != 10 {
+        return None;
+    }
+    let f: f64 = body.parse().ok()?;
+    Some(Token::Float(if negative { -f } else { f }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<Token> {
+        tokenize(1, line)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.token)
+            .collect()
+    }
+
+    fn error(line: &str) -> (usize, AsmErrorKind) {
+        let e = tokenize(1, line).unwrap_err();
+        (e.column, e.kind)
+    }
+
+    #[test]
+    fn splits_tokens_with_columns() {
+        let spanned = tokenize(3, "loop: Im8 -2, .x ; comment 1").unwrap();
+        let columns: Vec<usize> = spanned.iter().map(|s| s.column).collect();
+        assert_eq!(columns, [1, 5, 7, 11, 13, 15]);
+        assert_eq!(
+            tokens("loop: Im8 -2, .x ; comment 1"),
+            [
+                Token::Ident("loop".into()),
+                Token::Colon,
+                Token::Ident("Im8".into()),
+                Token::Int(-2),
+                Token::Comma,
+                Token::Ident(".x".into()),
+            ]
+        );
+        assert!(tokens("   ; only a comment").is_empty());
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(
+            tokens("0x1F 0o17 0b101 1_000 +7"),
+            [
+                Token::Int(31),
+                Token::Int(15),
+                Token::Int(5),
+                Token::Int(1000),
+                Token::Int(7),
+            ]
+        );
+        assert_eq!(tokens("0xffffffffffffffff"), [Token::Int(u64::MAX as i128)]);
+        assert_eq!(tokens("-0x10"), [Token::Int(-16)]);
+        assert_eq!(
+            tokens("1.5 -2e3 1e-2"),
+            [Token::Float(1.5), Token::Float(-2000.0), Token::Float(0.01),]
+        );
+        assert!(matches!(error("0x1g").1, AsmErrorKind::InvalidLiteral(_)));
+        assert!(matches!(error("12ab").1, AsmErrorKind::InvalidLiteral(_)));
+    }
+
+    #[test]
+    fn quoted_literals() {
+        assert_eq!(
+            tokens("'a' '\\n' '\\x41'"),
+            [Token::Int(97), Token::Int(10), Token::Int(65),]
+        );
+        assert_eq!(
+            tokens(r#""hi\t\"\\\0""#),
+            [Token::Str(b"hi\t\"\\\0".to_vec())]
+        );
+        assert_eq!(tokens("\"é\""), [Token::Str("é".as_bytes().to_vec())]);
+        assert_eq!(
+            error("Im8 'ab'"),
+            (5, AsmErrorKind::InvalidLiteral("'ab'".into()))
+        );
+        assert_eq!(error("\"abc"), (1, AsmErrorKind::UnterminatedLiteral));
+        assert_eq!(
+            error("'\\q'"),
+            (1, AsmErrorKind::InvalidEscape("\\q".into()))
+        );
+        assert_eq!(
+            error("'\\xz1'"),
+            (1, AsmErrorKind::InvalidEscape("\\xz1".into()))
+        );
+    }
+
+    #[test]
+    fn unexpected_characters() {
+        assert_eq!(error("Im8 #1"), (5, AsmErrorKind::UnexpectedChar('#')));
+    }
+}
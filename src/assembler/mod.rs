@@ -0,0 +1,499 @@
+mod lexer;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::instruction::Instructions;
+use crate::machine::program_memory::{ProgramMemory, Section, SourceLine, Symbol};
+use lexer::{tokenize, Spanned, Token};
+
+// Line oriented assembler for the textual `.sir` syntax:
+//
+//     ; comments run to the end of the line
+//     .data
+//     msg:  .ascii "hi\n"
+//     .code
+//     start: Im8 'a'
+//            Jz start        ; labels as branch targets
+//            Im64 msg        ; or as immediates
+//            Im64 1.5        ; floats are encoded as f64 bits
+//
+// Directives `.u8`, `.u16`, `.u32`, `.u64`, `.f64` and `.ascii`
+// emit raw values into the current section.
+pub fn assemble(source: &str) -> Result<ProgramMemory, AsmError> {
+    let mut asm = Assembler::new();
+    for (i, line) in source.lines().enumerate() {
+        asm.line(i + 1, line)?;
+    }
+    asm.finish()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmErrorKind {
+    UnexpectedChar(char),
+    UnterminatedLiteral,
+    InvalidEscape(String),
+    InvalidLiteral(String),
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    ExpectedOperand,
+    UnexpectedToken,
+    OutOfRange { width: usize },
+    FloatNotAllowed,
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedChar(c) => write!(f, "unexpected character {c:?}"),
+            Self::UnterminatedLiteral => write!(f, "unterminated literal"),
+            Self::InvalidEscape(e) => write!(f, "invalid escape sequence {e}"),
+            Self::InvalidLiteral(l) => write!(f, "invalid literal {l}"),
+            Self::UnknownMnemonic(m) => write!(f, "unknown instruction {m}"),
+            Self::UnknownDirective(d) => write!(f, "unknown directive {d}"),
+            Self::ExpectedOperand => write!(f, "expected an operand"),
+            Self::UnexpectedToken => write!(f, "unexpected token"),
+            Self::OutOfRange { width } => {
+                write!(f, "value does not fit in {width} byte operand")
+            }
+            Self::FloatNotAllowed => write!(f, "float literal needs an 8 byte operand"),
+            Self::UndefinedLabel(l) => write!(f, "undefined label {l}"),
+            Self::DuplicateLabel(l) => write!(f, "label {l} is already defined"),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// label operand patched after all labels are known
+struct Fixup {
+    section: Section,
+    at: usize,
+    width: usize,
+    signed: bool,
+    label: String,
+    line: usize,
+    column: usize,
+}
+
+struct Assembler {
+    prog: Vec<u8>,
+    data: Vec<u8>,
+    section: Section,
+    labels: HashMap<String, (Section, u64)>,
+    fixups: Vec<Fixup>,
+    lines: Vec<SourceLine>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            prog: Vec::new(),
+            data: Vec::new(),
+            section: Section::Code,
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+    fn bytes(&mut self) -> &mut Vec<u8> {
+        match self.section {
+            Section::Code => &mut self.prog,
+            Section::Data => &mut self.data,
+        }
+    }
+    fn line(&mut self, line: usize, text: &str) -> Result<(), AsmError> {
+        let tokens = tokenize(line, text)?;
+        let err = |column, kind| AsmError { line, column, kind };
+        let mut rest = &tokens[..];
+        // leading labels
+        while let [Spanned {
+            token: Token::Ident(name),
+            column,
+        }, Spanned {
+            token: Token::Colon,
+            ..
+        }, tail @ ..] = rest
+        {
+            let offset = self.bytes().len() as u64;
+            if self
+                .labels
+                .insert(name.clone(), (self.section, offset))
+                .is_some()
+            {
+                return Err(err(*column, AsmErrorKind::DuplicateLabel(name.clone())));
+            }
+            rest = tail;
+        }
+        let Some((head, operands)) = rest.split_first() else {
+            return Ok(());
+        };
+        let Token::Ident(name) = &head.token else {
+            return Err(err(head.column, AsmErrorKind::UnexpectedToken));
+        };
+        let operands = split_operands(line, head.column, operands)?;
+        if name.starts_with('.') {
+            return self.directive(line, head.column, name, &operands);
+        }
+        let ins = Instructions::of_mnemonic(name).ok_or(err(
+            head.column,
+            AsmErrorKind::UnknownMnemonic(name.clone()),
+        ))?;
+        let width = ins.im_size();
+        if self.section == Section::Code {
+            self.lines.push(SourceLine {
+                offset: self.prog.len() as u64,
+                line: line as u32,
+            });
+        }
+        self.bytes().push(ins.opcode());
+        match (width, &operands[..]) {
+            (0, []) => Ok(()),
+            (0, [op, ..]) => Err(err(op.column, AsmErrorKind::UnexpectedToken)),
+            (_, [op]) => self.value(line, op, width, ins.has_signed_im()),
+            (_, [_, op, ..]) => Err(err(op.column, AsmErrorKind::UnexpectedToken)),
+            (_, []) => Err(err(head.column + name.len(), AsmErrorKind::ExpectedOperand)),
+        }
+    }
+    fn directive(
+        &mut self,
+        line: usize,
+        column: usize,
+        name: &str,
+        operands: &[&Spanned],
+    ) -> Result<(), AsmError> {
+        let err = |column, kind| AsmError { line, column, kind };
+        let width = match name {
+            ".code" | ".data" => {
+                if let Some(op) = operands.first() {
+                    return Err(err(op.column, AsmErrorKind::UnexpectedToken));
+                }
+                self.section = match name {
+                    ".code" => Section::Code,
+                    _ => Section::Data,
+                };
+                return Ok(());
+            }
+            ".u8" | ".ascii" => 1,
+            ".u16" => 2,
+            ".u32" => 4,
+            ".u64" | ".f64" => 8,
+            _ => {
+                return Err(err(
+                    column,
+                    AsmErrorKind::UnknownDirective(name.to_string()),
+                ))
+            }
+        };
+        if operands.is_empty() {
+            return Err(err(column + name.len(), AsmErrorKind::ExpectedOperand));
+        }
+        for op in operands {
+            match (name, &op.token) {
+                (".ascii", Token::Str(s)) => self.bytes().extend_from_slice(s),
+                (".ascii", _) => return Err(err(op.column, AsmErrorKind::UnexpectedToken)),
+                (".f64", Token::Int(v)) => {
+                    let bits = (*v as f64).to_bits();
+                    self.bytes().extend_from_slice(&bits.to_le_bytes())
+                }
+                _ => self.value(line, op, width, false)?,
+            }
+        }
+        Ok(())
+    }
+    // emit a `width` bytes little endian operand
+    fn value(
+        &mut self,
+        line: usize,
+        op: &Spanned,
+        width: usize,
+        signed: bool,
+    ) -> Result<(), AsmError> {
+        let err = |kind| AsmError {
+            line,
+            column: op.column,
+            kind,
+        };
+        let v = match &op.token {
+            Token::Int(v) => {
+                encode_int(*v, width, signed).ok_or(err(AsmErrorKind::OutOfRange { width }))?
+            }
+            Token::Float(f) if width == size_of::<f64>() => f.to_bits(),
+            Token::Float(_) => return Err(err(AsmErrorKind::FloatNotAllowed)),
+            Token::Ident(label) => {
+                let section = self.section;
+                let at = self.bytes().len();
+                self.fixups.push(Fixup {
+                    section,
+                    at,
+                    width,
+                    signed,
+                    label: label.clone(),
+                    line,
+                    column: op.column,
+                });
+                0
+            }
+            _ => return Err(err(AsmErrorKind::UnexpectedToken)),
+        };
+        self.bytes().extend_from_slice(&v.to_le_bytes()[..width]);
+        Ok(())
+    }
+    fn finish(mut self) -> Result<ProgramMemory, AsmError> {
+        for f in &self.fixups {
+            let err = |kind| AsmError {
+                line: f.line,
+                column: f.column,
+                kind,
+            };
+            let (_, v) = *self
+                .labels
+                .get(&f.label)
+                .ok_or(err(AsmErrorKind::UndefinedLabel(f.label.clone())))?;
+            let v = encode_int(v as i128, f.width, f.signed)
+                .ok_or(err(AsmErrorKind::OutOfRange { width: f.width }))?;
+            let bytes = match f.section {
+                Section::Code => &mut self.prog,
+                Section::Data => &mut self.data,
+            };
+            bytes[f.at..f.at + f.width].copy_from_slice(&v.to_le_bytes()[..f.width]);
+        }
+        let mut symbols: Vec<Symbol> = self
+            .labels
+            .into_iter()
+            .map(|(name, (section, offset))| Symbol {
+                name,
+                section,
+                offset,
+            })
+            .collect();
+        symbols.sort_by(|a, b| (a.section, a.offset, &a.name).cmp(&(b.section, b.offset, &b.name)));
+        Ok(ProgramMemory::new(self.prog, self.data)
+            .with_symbols(symbols)
+            .with_lines(self.lines))
+    }
+}
+
+// comma separated operands, each a single token
+fn split_operands(
+    line: usize,
+    column: usize,
+    tokens: &[Spanned],
+) -> Result<Vec<&Spanned>, AsmError> {
+    let mut operands = Vec::new();
+    let mut expect_value = true;
+    for t in tokens {
+        match (&t.token, expect_value) {
+            (Token::Comma, false) => expect_value = true,
+            (Token::Comma | Token::Colon, _) | (_, false) => {
+                return Err(AsmError {
+                    line,
+                    column: t.column,
+                    kind: AsmErrorKind::UnexpectedToken,
+                })
+            }
+            (_, true) => {
+                operands.push(t);
+                expect_value = false;
+            }
+        }
+    }
+    if expect_value && !operands.is_empty() {
+        let column = tokens.last().map_or(column, |t| t.column + 1);
+        return Err(AsmError {
+            line,
+            column,
+            kind: AsmErrorKind::ExpectedOperand,
+        });
+    }
+    Ok(operands)
+}
+
+// integer as a `width` bytes operand, negative values in two's complement,
+// a signed operand is limited to what sign extends back to `v`
+fn encode_int(v: i128, width: usize, signed: bool) -> Option<u64> {
+    let bits = 8 * width as u32;
+    let mask = (1i128 << bits) - 1;
+    let max = match signed {
+        true => mask >> 1,
+        false => mask,
+    };
+    let min = -(1i128 << (bits - 1));
+    (min..=max)
+        .contains(&v)
+        .then_some((v as u64) & (mask as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instructions as I;
+
+    fn error(source: &str) -> (usize, usize, AsmErrorKind) {
+        let e = assemble(source).unwrap_err();
+        (e.line, e.column, e.kind)
+    }
+
+    #[test]
+    fn encodes_instructions_and_operands() {
+        let program = assemble(
+            "
+            nop
+            IM8 255
+            Im16 -1
+            Im32 0x12345678
+            Im64 1.5
+            Im8 'a'
+            Add",
+        )
+        .unwrap();
+        let mut expected = vec![I::Nop.opcode(), I::Im8.opcode(), 255];
+        expected.extend([I::Im16.opcode(), 0xff, 0xff]);
+        expected.extend([I::Im32.opcode(), 0x78, 0x56, 0x34, 0x12]);
+        expected.push(I::Im64.opcode());
+        expected.extend(1.5f64.to_bits().to_le_bytes());
+        expected.extend([I::Im8.opcode(), b'a', I::Add.opcode()]);
+        assert_eq!(program.prog(), expected);
+        assert!(program.data().is_empty());
+        let lines: Vec<(u64, u32)> = program.lines().iter().map(|l| (l.offset, l.line)).collect();
+        assert_eq!(
+            lines,
+            [(0, 2), (1, 3), (3, 4), (6, 5), (11, 6), (20, 7), (22, 8)]
+        );
+    }
+
+    #[test]
+    fn resolves_labels_in_both_sections() {
+        let program = assemble(
+            "
+            .data
+            first:  .u8 1, 2
+            second: .u16 0x0304
+                    .u32 5
+                    .u64 first, second
+                    .f64 2, 0.5
+                    .ascii \"ok\"
+            .code
+            start:  J end
+                    Im8 second
+            end: again: Jz start",
+        )
+        .unwrap();
+        let mut data = vec![1, 2, 4, 3, 5, 0, 0, 0];
+        data.extend(0u64.to_le_bytes());
+        data.extend(2u64.to_le_bytes());
+        data.extend(2f64.to_bits().to_le_bytes());
+        data.extend(0.5f64.to_bits().to_le_bytes());
+        data.extend(b"ok");
+        assert_eq!(program.data(), data);
+        let mut prog = vec![I::J.opcode()];
+        prog.extend(11u64.to_le_bytes());
+        prog.extend([I::Im8.opcode(), 2, I::Jz.opcode()]);
+        prog.extend(0u64.to_le_bytes());
+        assert_eq!(program.prog(), prog);
+        let symbols: Vec<(&str, Section, u64)> = program
+            .symbols()
+            .iter()
+            .map(|s| (s.name.as_str(), s.section, s.offset))
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                ("start", Section::Code, 0),
+                ("again", Section::Code, 11),
+                ("end", Section::Code, 11),
+                ("first", Section::Data, 0),
+                ("second", Section::Data, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn operand_ranges() {
+        assert_eq!(assemble("Im8 -128").unwrap().prog()[1], 0x80);
+        assert_eq!(
+            error("Im8 256"),
+            (1, 5, AsmErrorKind::OutOfRange { width: 1 })
+        );
+        assert_eq!(
+            error("Im8 -129"),
+            (1, 5, AsmErrorKind::OutOfRange { width: 1 })
+        );
+        assert_eq!(error("Im16 1.5"), (1, 6, AsmErrorKind::FloatNotAllowed));
+        // a code label past 255 does not fit an 8 bit operand
+        let far = format!("Im8 far\n{}far: Nop", "Nop\n".repeat(300));
+        assert_eq!(error(&far), (1, 5, AsmErrorKind::OutOfRange { width: 1 }));
+    }
+
+    #[test]
+    fn signed_operand_ranges() {
+        let program = assemble("Im8s 127\n Im8s -128\n Im16s -32768\n Im32s 0x7fffffff").unwrap();
+        assert_eq!(program.prog()[1..4], [0x7f, I::Im8s.opcode(), 0x80]);
+        for (src, width) in [
+            ("Im8s 128", 1),
+            ("Im8s 255", 1),
+            ("Im16s 0x8000", 2),
+            ("Im32s 0x80000000", 4),
+            ("Im32s -2147483649", 4),
+        ] {
+            let column = src.find(' ').unwrap() + 2;
+            assert_eq!(
+                error(src),
+                (1, column, AsmErrorKind::OutOfRange { width }),
+                "{src}"
+            );
+        }
+        // unsigned forms keep the full range
+        assert!(assemble("Im8 255\n Im32 0x80000000").is_ok());
+        let far = format!("Im8s far\n{}far: Nop", "Nop\n".repeat(200));
+        assert_eq!(error(&far), (1, 6, AsmErrorKind::OutOfRange { width: 1 }));
+    }
+
+    #[test]
+    fn reports_errors_with_positions() {
+        let cases = [
+            ("Foo 1", (1, 1, AsmErrorKind::UnknownMnemonic("Foo".into()))),
+            (
+                "Nop\n.text",
+                (2, 1, AsmErrorKind::UnknownDirective(".text".into())),
+            ),
+            ("  Im8", (1, 6, AsmErrorKind::ExpectedOperand)),
+            ("Add 1", (1, 5, AsmErrorKind::UnexpectedToken)),
+            ("Im8 1, 2", (1, 8, AsmErrorKind::UnexpectedToken)),
+            ("Im8 1 2", (1, 7, AsmErrorKind::UnexpectedToken)),
+            (".u8 1,", (1, 7, AsmErrorKind::ExpectedOperand)),
+            (".u8", (1, 4, AsmErrorKind::ExpectedOperand)),
+            (".ascii 1", (1, 8, AsmErrorKind::UnexpectedToken)),
+            (".data 1", (1, 7, AsmErrorKind::UnexpectedToken)),
+            ("1", (1, 1, AsmErrorKind::UnexpectedToken)),
+            (
+                "J nowhere",
+                (1, 3, AsmErrorKind::UndefinedLabel("nowhere".into())),
+            ),
+            (
+                "a: Nop\na: Nop",
+                (2, 1, AsmErrorKind::DuplicateLabel("a".into())),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(error(source), expected, "{source}");
+        }
+        let e = assemble("Nop\n  Im8 300").unwrap_err();
+        assert_eq!(e.to_string(), "2:7: value does not fit in 1 byte operand");
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+use stackir::assembler::assemble;
+use stackir::disassembler::{decode_at, disassemble, DecodedInstruction};
+use stackir::instruction::Instructions as I;
+use stackir::machine::heap::HEAP_BASE;
+use stackir::machine::program_memory::{ProgramMemory, Section};
+use stackir::machine::watch::{Access, Watchpoint};
+use stackir::machine::{Machine, MachineState};
+
+// interactive step debugger
+//
+//   stackir-dbg <program.sirb | program.sir>
+
+const HELP: &str = "\
+commands:
+  s, step [n]           run n instructions (1)
+  n, next               step over Call and CallA
+  c, continue           run until a breakpoint or the program stops
+  b, break [loc]        set a breakpoint at an address or label, list them without loc
+  d, delete [id]        delete a breakpoint, all without id
+  w, watch [addr] [len] [r|w|rw]
+                        stop when runtime memory is accessed (8 bytes, w),
+                        list watchpoints without addr
+  uw, unwatch <id>      delete a watchpoint
+  p, print [i64|u64|f64]
+                        print both stacks, top last (i64)
+  x <addr> [len]        hexdump runtime memory or the heap (64 bytes)
+  l, list [n]           disassemble n instructions around pc (5)
+  pc <loc>              set pc to an address or label
+  h, help               this text
+  q, quit
+an empty line repeats the last command, addresses are decimal or 0x hex";
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: stackir-dbg <program.sirb | program.sir>");
+        return ExitCode::FAILURE;
+    };
+    let program = match load(&path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut dbg = Debugger::new(program);
+    dbg.where_am_i();
+    let mut last = String::new();
+    let stdin = io::stdin();
+    loop {
+        print!("(dbg) ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let line = match line.trim() {
+            "" => last.clone(),
+            l => l.to_string(),
+        };
+        if !dbg.command(&line) {
+            break;
+        }
+        last = line;
+    }
+    ExitCode::SUCCESS
+}
+
+// `.sirb` images are loaded as is, anything else is assembled
+fn load(path: &str) -> Result<ProgramMemory, String> {
+    if path.ends_with(".sirb") {
+        let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        return ProgramMemory::load_from(io::BufReader::new(file)).map_err(|e| e.to_string());
+    }
+    let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    assemble(&source).map_err(|e| e.to_string())
+}
+
+struct Debugger {
+    program: ProgramMemory,
+    listing: Vec<DecodedInstruction>,
+    machine: Machine,
+    // id, address
+    breakpoints: BTreeMap<usize, u64>,
+    next_id: usize,
+}
+
+impl Debugger {
+    fn new(program: ProgramMemory) -> Self {
+        Self {
+            listing: disassemble(&program),
+            program,
+            machine: Machine::new(),
+            breakpoints: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+
+    // run one command line, false to quit
+    fn command(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let Some(cmd) = words.next() else {
+            return true;
+        };
+        let args: Vec<&str> = words.collect();
+        let res = match cmd {
+            "s" | "step" => self.step_n(&args),
+            "n" | "next" => self.next(),
+            "c" | "continue" => self.cont(),
+            "b" | "break" => self.set_break(&args),
+            "d" | "delete" => self.delete(&args),
+            "w" | "watch" => self.watch(&args),
+            "uw" | "unwatch" => self.unwatch(&args),
+            "p" | "print" => self.print(&args),
+            "x" => self.hexdump(&args),
+            "l" | "list" => self.list(&args),
+            "pc" => self.set_pc(&args),
+            "h" | "help" => {
+                println!("{HELP}");
+                Ok(())
+            }
+            "q" | "quit" => return false,
+            _ => Err(format!("unknown command `{cmd}`, try help")),
+        };
+        if let Err(e) = res {
+            println!("{e}");
+        }
+        true
+    }
+
+    // address or code label
+    fn location(&self, s: &str) -> Result<u64, String> {
+        if let Some(sym) = self.program.symbol(s) {
+            return match sym.section {
+                Section::Code => Ok(sym.offset),
+                Section::Data => Err(format!("`{s}` is a data label")),
+            };
+        }
+        number(s)
+    }
+
+    // execute one instruction, false when the machine is not running afterwards
+    fn step_one(&mut self) -> Result<bool, String> {
+        // an Interupt only pauses the debugger
+        self.machine.resume();
+        match self.machine.state() {
+            MachineState::Running => {}
+            s => return Err(format!("program is not running: {}", describe(&s))),
+        }
+        let _ = self.machine.run_program(&self.program);
+        Ok(self.machine.state() == MachineState::Running)
+    }
+
+    fn step_n(&mut self, args: &[&str]) -> Result<(), String> {
+        let n = match args.first() {
+            Some(a) => number(a)?,
+            None => 1,
+        };
+        for _ in 0..n {
+            if !self.step_one()? {
+                break;
+            }
+        }
+        self.where_am_i();
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), String> {
+        let pc = self.machine.pc();
+        let call = (pc < self.program.prog_len() as u64)
+            .then(|| decode_at(self.program.prog(), pc))
+            .filter(|d| matches!(d.instruction(), Some((I::Call | I::CallA, _))));
+        let Some(call) = call else {
+            return self.step_n(&[]);
+        };
+        let depth = self.machine.return_stack().len();
+        let mut running = self.step_one()?;
+        while running
+            && !(self.machine.pc() == call.next_offset()
+                && self.machine.return_stack().len() == depth)
+            && !self.at_breakpoint()
+        {
+            running = self.step_one()?;
+        }
+        self.where_am_i();
+        Ok(())
+    }
+
+    fn cont(&mut self) -> Result<(), String> {
+        // leave a breakpoint we are stopped on
+        let mut running = self.step_one()?;
+        while running && !self.at_breakpoint() {
+            running = self.step_one()?;
+        }
+        self.where_am_i();
+        Ok(())
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.machine.pc();
+        self.breakpoints.values().any(|b| *b == pc)
+    }
+
+    fn set_break(&mut self, args: &[&str]) -> Result<(), String> {
+        let Some(loc) = args.first() else {
+            if self.breakpoints.is_empty() {
+                println!("no breakpoints");
+            }
+            for (id, addr) in &self.breakpoints {
+                println!("{id}: {addr:#x}{}", self.label_of(*addr));
+            }
+            return Ok(());
+        };
+        let addr = self.location(loc)?;
+        if !self.listing.iter().any(|d| d.offset == addr) {
+            println!("warning: {addr:#x} is not an instruction boundary");
+        }
+        self.breakpoints.insert(self.next_id, addr);
+        println!("breakpoint {} at {addr:#x}", self.next_id);
+        self.next_id += 1;
+        Ok(())
+    }
+
+    fn delete(&mut self, args: &[&str]) -> Result<(), String> {
+        match args.first() {
+            None => self.breakpoints.clear(),
+            Some(id) => {
+                let id = number(id)? as usize;
+                self.breakpoints
+                    .remove(&id)
+                    .ok_or(format!("no breakpoint {id}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn watch(&mut self, args: &[&str]) -> Result<(), String> {
+        let Some(addr) = args.first() else {
+            let points = self.machine.watchpoints();
+            if points.is_empty() {
+                println!("no watchpoints");
+            }
+            for (id, w) in points {
+                let access = match (w.read, w.write) {
+                    (true, true) => "rw",
+                    (true, false) => "r",
+                    _ => "w",
+                };
+                println!(
+                    "{id}: {:#x}..{:#x} {access}",
+                    w.start,
+                    w.start.saturating_add(w.len)
+                );
+            }
+            return Ok(());
+        };
+        let addr = number(addr)?;
+        let len = match args.get(1) {
+            Some(l) => number(l)?,
+            None => 8,
+        };
+        let w = match args.get(2).copied().unwrap_or("w") {
+            "r" => Watchpoint::reads(addr, len),
+            "w" => Watchpoint::writes(addr, len),
+            "rw" => Watchpoint::accesses(addr, len),
+            a => return Err(format!("unknown access `{a}`, use r, w or rw")),
+        };
+        let id = self.machine.watch(w.interrupting());
+        println!(
+            "watchpoint {id} at {addr:#x}..{:#x}",
+            addr.saturating_add(len)
+        );
+        Ok(())
+    }
+
+    fn unwatch(&mut self, args: &[&str]) -> Result<(), String> {
+        let id = number(args.first().ok_or("usage: unwatch <id>")?)? as usize;
+        self.machine
+            .unwatch(id)
+            .map(|_| ())
+            .ok_or(format!("no watchpoint {id}"))
+    }
+
+    fn print(&self, args: &[&str]) -> Result<(), String> {
+        let fmt: fn(u64) -> String = match args.first().copied().unwrap_or("i64") {
+            "i64" => |v| (v as i64).to_string(),
+            "u64" => |v| v.to_string(),
+            "f64" => |v| f64::from_bits(v).to_string(),
+            f => return Err(format!("unknown format `{f}`, use i64, u64 or f64")),
+        };
+        let show = |values: &[u64]| values.iter().map(|v| fmt(*v)).collect::<Vec<_>>();
+        println!("calculation: {:?}", show(self.machine.calculation_stack()));
+        println!("return:      {:?}", show(self.machine.return_stack()));
+        println!("fp:          {:#x}", self.machine.fp());
+        Ok(())
+    }
+
+    fn hexdump(&self, args: &[&str]) -> Result<(), String> {
+        let start = number(args.first().ok_or("usage: x <addr> [len]")?)?;
+        let len = match args.get(1) {
+            Some(l) => number(l)?,
+            None => 64,
+        };
+        // heap addresses are shown as they are, indexed from HEAP_BASE
+        let (base, memory, name) = match start >= HEAP_BASE {
+            true => (HEAP_BASE, self.machine.heap_memory(), "heap"),
+            false => (0, self.machine.memory(), "runtime memory"),
+        };
+        let end = start.saturating_add(len).min(base + memory.len() as u64);
+        if start >= end {
+            return Err(format!(
+                "{start:#x} is outside {name} of {} bytes",
+                memory.len()
+            ));
+        }
+        for line in (start..end).step_by(16) {
+            let bytes = &memory[(line - base) as usize..(end.min(line + 16) - base) as usize];
+            let hex: String = bytes.iter().map(|b| format!("{b:02x} ")).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                    true => b as char,
+                    false => '.',
+                })
+                .collect();
+            println!("{line:08x}  {hex:48} {ascii}");
+        }
+        Ok(())
+    }
+
+    fn list(&self, args: &[&str]) -> Result<(), String> {
+        let n = match args.first() {
+            Some(a) => number(a)? as usize,
+            None => 5,
+        };
+        let pc = self.machine.pc();
+        let at = self.listing.partition_point(|d| d.offset <= pc);
+        if at == 0 || self.listing[at - 1].offset != pc {
+            // pc inside an instruction of the linear listing
+            self.show_current();
+        }
+        let (start, end) = (at.saturating_sub(n.saturating_add(1)), at.saturating_add(n));
+        for d in &self.listing[start..end.min(self.listing.len())] {
+            self.show(d);
+        }
+        Ok(())
+    }
+
+    fn set_pc(&mut self, args: &[&str]) -> Result<(), String> {
+        let addr = self.location(args.first().ok_or("usage: pc <addr|label>")?)?;
+        self.machine.set_pc(addr);
+        self.where_am_i();
+        Ok(())
+    }
+
+    // triggered watchpoints, the state when not running, then the instruction at pc
+    fn where_am_i(&mut self) {
+        for e in self.machine.take_watch_events() {
+            let access = match e.access {
+                Access::Read => format!("read {:#x}", e.new),
+                Access::Write => format!("{:#x} -> {:#x}", e.old, e.new),
+            };
+            println!(
+                "watchpoint {}: {:08x} {} bytes at {:#x}: {access}",
+                e.id, e.pc, e.width, e.addr
+            );
+        }
+        match self.machine.state() {
+            MachineState::Running => {}
+            s => println!("{}", describe(&s)),
+        }
+        self.show_current();
+    }
+
+    fn show_current(&self) {
+        let pc = self.machine.pc();
+        if pc < self.program.prog_len() as u64 {
+            self.show(&decode_at(self.program.prog(), pc));
+        } else {
+            println!("=> {pc:08x}  <end of program>");
+        }
+    }
+
+    fn show(&self, d: &DecodedInstruction) {
+        let label = self.label_of(d.offset);
+        if !label.is_empty() {
+            println!("  {}:", label.trim_start());
+        }
+        let marker = match (
+            d.offset == self.machine.pc(),
+            self.breakpoints.values().any(|b| *b == d.offset),
+        ) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            (false, false) => "  ",
+        };
+        println!("{marker} {d}");
+    }
+
+    // " name" of a code label at `addr`, empty if there is none
+    fn label_of(&self, addr: u64) -> String {
+        self.program
+            .symbols()
+            .iter()
+            .find(|s| s.section == Section::Code && s.offset == addr)
+            .map(|s| format!(" {}", s.name))
+            .unwrap_or_default()
+    }
+}
+
+fn describe(state: &MachineState) -> String {
+    match state {
+        MachineState::Running => "running".to_string(),
+        MachineState::Interupted => "interrupted".to_string(),
+        MachineState::Ended => "program ended".to_string(),
+        MachineState::Trapped(t) => format!("trapped: {t}"),
+    }
+}
+
+fn number(s: &str) -> Result<u64, String> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|_| format!("`{s}` is not a number"))
+}
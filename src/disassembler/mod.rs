@@ -0,0 +1,268 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::instruction::Instructions;
+use crate::machine::program_memory::ProgramMemory;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub offset: u64,
+    pub bytes: Vec<u8>,
+    pub kind: Decoded,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    // a valid instruction with its immediate operand if it has one
+    Instruction { ins: Instructions, im: Option<u64> },
+    // the byte is not an opcode
+    InvalidOpcode(u8),
+    // the immediate runs past the end of the program
+    Truncated(Instructions),
+}
+
+impl DecodedInstruction {
+    // offset of the following instruction
+    pub fn next_offset(&self) -> u64 {
+        self.offset + self.bytes.len() as u64
+    }
+    pub fn instruction(&self) -> Option<(&Instructions, Option<u64>)> {
+        match &self.kind {
+            Decoded::Instruction { ins, im } => Some((ins, *im)),
+            _ => None,
+        }
+    }
+}
+
+// decode the single instruction at `offset`, which must be inside `prog`
+pub fn decode_at(prog: &[u8], offset: u64) -> DecodedInstruction {
+    let start = offset as usize;
+    let byte = prog[start];
+    let Some(ins) = Instructions::of_opcode(byte) else {
+        return DecodedInstruction {
+            offset,
+            bytes: vec![byte],
+            kind: Decoded::InvalidOpcode(byte),
+        };
+    };
+    let width = ins.im_size();
+    let end = start + 1 + width;
+    if end > prog.len() {
+        return DecodedInstruction {
+            offset,
+            bytes: prog[start..].to_vec(),
+            kind: Decoded::Truncated(ins),
+        };
+    }
+    let im = (width > 0).then(|| {
+        let mut buf = [0u8; 8];
+        buf[..width].copy_from_slice(&prog[start + 1..end]);
+        u64::from_le_bytes(buf)
+    });
+    DecodedInstruction {
+        offset,
+        bytes: prog[start..end].to_vec(),
+        kind: Decoded::Instruction { ins, im },
+    }
+}
+
+// decode the whole program linearly, undecodable bytes are kept as single byte entries
+pub fn disassemble(program: &ProgramMemory) -> Vec<DecodedInstruction> {
+    let prog = program.prog();
+    let mut res = Vec::new();
+    let mut offset = 0;
+    while offset < prog.len() as u64 {
+        let d = decode_at(prog, offset);
+        offset = d.next_offset();
+        res.push(d);
+    }
+    res
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decoded::Instruction { ins, im: None } => write!(f, "{}", ins.mnemonic()),
+            Decoded::Instruction { ins, im: Some(im) } if ins.is_direct_branch() => {
+                write!(f, "{} {im:#x}", ins.mnemonic())
+            }
+            Decoded::Instruction { ins, im: Some(im) } if ins.has_signed_im() => {
+                let shift = 64 - 8 * ins.im_size() as u32;
+                write!(f, "{} {}", ins.mnemonic(), (*im << shift) as i64 >> shift)
+            }
+            Decoded::Instruction { ins, im: Some(im) } => write!(f, "{} {im}", ins.mnemonic()),
+            Decoded::InvalidOpcode(b) => write!(f, "<invalid opcode {b:#04x}>"),
+            Decoded::Truncated(ins) => write!(f, "<truncated {}>", ins.mnemonic()),
+        }
+    }
+}
+
+// offset, raw bytes and the decoded instruction
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = String::new();
+        for b in &self.bytes {
+            write!(bytes, "{b:02x} ")?;
+        }
+        write!(f, "{:08x}  {bytes:27} {}", self.offset, self.kind)
+    }
+}
+
+pub struct Listing<'a>(pub &'a [DecodedInstruction]);
+
+impl fmt::Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for d in self.0 {
+            writeln!(f, "{d}")?;
+        }
+        Ok(())
+    }
+}
+
+// assembler source that assembles back to the same program,
+// branch targets on instruction boundaries get `L_<offset>` labels
+pub fn to_assembly(program: &ProgramMemory) -> String {
+    let decoded = disassemble(program);
+    let boundaries: BTreeSet<u64> = decoded.iter().map(|d| d.offset).collect();
+    let targets: BTreeSet<u64> = decoded
+        .iter()
+        .filter_map(|d| match d.instruction() {
+            Some((ins, Some(im))) if ins.is_direct_branch() && boundaries.contains(&im) => Some(im),
+            _ => None,
+        })
+        .collect();
+    let mut out = String::new();
+    for d in &decoded {
+        if targets.contains(&d.offset) {
+            let _ = writeln!(out, "L_{:x}:", d.offset);
+        }
+        let _ = match &d.kind {
+            Decoded::Instruction { ins, im: Some(im) }
+                if ins.is_direct_branch() && targets.contains(im) =>
+            {
+                writeln!(out, "    {} L_{im:x}", ins.mnemonic())
+            }
+            Decoded::Instruction { .. } => writeln!(out, "    {}", d.kind),
+            _ => writeln!(out, "    .u8 {}    ; {}", hex_list(&d.bytes), d.kind),
+        };
+    }
+    if !program.data().is_empty() {
+        out.push_str(".data\n");
+        for row in program.data().chunks(16) {
+            let _ = writeln!(out, "    .u8 {}", hex_list(row));
+        }
+    }
+    out
+}
+
+fn hex_list(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:#04x}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::instruction::Instructions as I;
+
+    #[test]
+    fn decodes_instructions_and_bad_bytes() {
+        let program = ProgramMemory::new(
+            vec![
+                I::Nop.opcode(),
+                I::Im16.opcode(),
+                0x34,
+                0x12,
+                0xff,
+                I::Im32.opcode(),
+                1,
+            ],
+            vec![],
+        );
+        let decoded = disassemble(&program);
+        let kinds: Vec<&Decoded> = decoded.iter().map(|d| &d.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &Decoded::Instruction {
+                    ins: I::Nop,
+                    im: None
+                },
+                &Decoded::Instruction {
+                    ins: I::Im16,
+                    im: Some(0x1234)
+                },
+                &Decoded::InvalidOpcode(0xff),
+                &Decoded::Truncated(I::Im32),
+            ]
+        );
+        let offsets: Vec<u64> = decoded.iter().map(|d| d.offset).collect();
+        assert_eq!(offsets, [0, 1, 4, 5]);
+        assert_eq!(decoded[3].bytes, [I::Im32.opcode(), 1]);
+        assert_eq!(decoded[3].next_offset(), 7);
+    }
+
+    #[test]
+    fn listing_shows_offsets_bytes_and_operands() {
+        let program = assemble("start: Im8 7\n J start\n Nop").unwrap();
+        let listing = Listing(&disassemble(&program)).to_string();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], format!("00000000  08 07 {:21} Im8 7", ""));
+        assert!(lines[1].starts_with("00000002  "));
+        assert!(lines[1].ends_with(" J 0x0"));
+        assert!(lines[2].ends_with(" Nop"));
+        assert_eq!(Decoded::Truncated(I::Im64).to_string(), "<truncated Im64>");
+        let signed = Decoded::Instruction {
+            ins: I::Im16s,
+            im: Some(0xfffe),
+        };
+        assert_eq!(signed.to_string(), "Im16s -2");
+        assert_eq!(
+            Decoded::InvalidOpcode(0xfe).to_string(),
+            "<invalid opcode 0xfe>"
+        );
+    }
+
+    #[test]
+    fn assembly_round_trips() {
+        let sources = [
+            "
+            .data
+                .u64 1, 2
+                .ascii \"text\"
+            .code
+            top:    Im8 1
+                    Jz done
+                    Call top
+                    Im64 -5
+                    Im32s -7
+                    Im16s 300
+                    Im8s -128
+                    Enter 16
+                    LoadLocal64 8
+                    J 3
+            done:   Ret",
+            "Nop",
+        ];
+        for source in sources {
+            let program = assemble(source).unwrap();
+            let text = to_assembly(&program);
+            let again = assemble(&text).unwrap();
+            assert_eq!(again.prog(), program.prog(), "{text}");
+            assert_eq!(again.data(), program.data(), "{text}");
+        }
+        let text = to_assembly(&assemble("a: Jnz a\n J 1").unwrap());
+        assert!(text.starts_with("L_0:\n    Jnz L_0\n    J 0x1\n"), "{text}");
+
+        // undecodable bytes come back as data in the code section
+        let program = ProgramMemory::new(vec![0xff, I::Im16.opcode(), 1], vec![]);
+        let again = assemble(&to_assembly(&program)).unwrap();
+        assert_eq!(again.prog(), program.prog());
+    }
+}
@@ -1,105 +1,259 @@
+pub mod program_builder;
 pub mod program_maker;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-#[allow(dead_code)]
-#[repr(u8)]
-#[derive(Debug, Eq, PartialEq, Clone, TryFromPrimitive, IntoPrimitive)]
-pub enum Instructions {
-    Nop = 0,
-    Interupt,
+// version of the opcode table, bumped whenever instructions are added
+pub const ISA_VERSION: u16 = 9;
+
+// values an instruction pops and pushes on the calculation stack and the return stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StackEffect {
+    pub pops: u8,
+    pub pushes: u8,
+    pub r_pops: u8,
+    pub r_pushes: u8,
+}
+
+impl StackEffect {
+    pub const fn new(pops: u8, pushes: u8) -> Self {
+        Self {
+            pops,
+            pushes,
+            r_pops: 0,
+            r_pushes: 0,
+        }
+    }
+    pub const fn with_return(pops: u8, pushes: u8, r_pops: u8, r_pushes: u8) -> Self {
+        Self {
+            pops,
+            pushes,
+            r_pops,
+            r_pushes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Utility,
+    Stack,
+    Memory,
+    Branch,
+    Int,
+    Float,
+    Conversion,
+}
+
+// static description of an opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstrInfo {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    // size in bytes of the immediate operand following the opcode
+    pub im_size: usize,
+    // HostCall is listed as 0/0, its real effect depends on the host function
+    pub effect: StackEffect,
+    pub category: Category,
+}
+
+// defines the Instructions enum and its InstrInfo table from one list,
+// opcodes are assigned in order starting from 0
+macro_rules! instructions {
+    ($(
+        $name:ident, $im:literal, $pops:literal, $pushes:literal,
+        $r_pops:literal, $r_pushes:literal, $category:ident;
+    )*) => {
+        #[allow(dead_code)]
+        #[repr(u8)]
+        #[derive(Debug, Eq, PartialEq, Clone, TryFromPrimitive, IntoPrimitive)]
+        pub enum Instructions {
+            $($name,)*
+        }
+
+        static INFO: &[InstrInfo] = &[$(
+            InstrInfo {
+                opcode: Instructions::$name as u8,
+                mnemonic: stringify!($name),
+                im_size: $im,
+                effect: StackEffect::with_return($pops, $pushes, $r_pops, $r_pushes),
+                category: Category::$category,
+            },
+        )*];
+    };
+}
+
+instructions! {
+    // name,  immediate bytes, pops, pushes, return pops, return pushes, category
+    Nop,       0, 0, 0, 0, 0, Utility;
+    Interupt,  0, 0, 0, 0, 0, Utility;
     // stack manipulation
-    FromR,
-    ToR,
-    Swap,
-    Over,
-    Dup,
-    Discard,
-    Im8,
-    Im16,
-    Im32,
-    Im64,
+    FromR,     0, 0, 1, 1, 0, Stack;
+    ToR,       0, 1, 0, 0, 1, Stack;
+    Swap,      0, 2, 2, 0, 0, Stack;
+    Over,      0, 2, 3, 0, 0, Stack;
+    Dup,       0, 1, 2, 0, 0, Stack;
+    Discard,   0, 1, 0, 0, 0, Stack;
+    Im8,       1, 0, 1, 0, 0, Stack;
+    Im16,      2, 0, 1, 0, 0, Stack;
+    Im32,      4, 0, 1, 0, 0, Stack;
+    Im64,      8, 0, 1, 0, 0, Stack;
     // Memory manipulation
-    Store8,
-    Load8,
-    Store16,
-    Load16,
-    Store32,
-    Load32,
-    Store64,
-    Load64,
-    Alloc,
-    Dealloc,
-    LoadData8,
-    LoadData16,
-    LoadData32,
-    LoadData64,
+    Store8,    0, 2, 0, 0, 0, Memory;
+    Load8,     0, 1, 1, 0, 0, Memory;
+    Store16,   0, 2, 0, 0, 0, Memory;
+    Load16,    0, 1, 1, 0, 0, Memory;
+    Store32,   0, 2, 0, 0, 0, Memory;
+    Load32,    0, 1, 1, 0, 0, Memory;
+    Store64,   0, 2, 0, 0, 0, Memory;
+    Load64,    0, 1, 1, 0, 0, Memory;
+    Alloc,     1, 0, 0, 0, 0, Memory;
+    Dealloc,   1, 0, 0, 0, 0, Memory;
+    LoadData8, 0, 1, 1, 0, 0, Memory;
+    LoadData16, 0, 1, 1, 0, 0, Memory;
+    LoadData32, 0, 1, 1, 0, 0, Memory;
+    LoadData64, 0, 1, 1, 0, 0, Memory;
     // Branch
-    J,
-    Jz,
-    Jnz,
+    J,         8, 0, 0, 0, 0, Branch;
+    Jz,        8, 1, 0, 0, 0, Branch;
+    Jnz,       8, 1, 0, 0, 0, Branch;
     // Computed Branch
-    Ja,
+    Ja,        0, 1, 0, 0, 0, Branch;
     // arithmatic (i64)
-    Add,
-    Addu,
-    Sub,
-    Subu,
-    Mul,
-    Mulu,
-    Div,
-    Divu,
-    Mod,
-    Modu,
-    Neg,
-    Shl,
-    Shlr,
-    Shar,
-    PopCnt,
+    Add,       0, 2, 1, 0, 0, Int;
+    Addu,      0, 2, 1, 0, 0, Int;
+    Sub,       0, 2, 1, 0, 0, Int;
+    Subu,      0, 2, 1, 0, 0, Int;
+    Mul,       0, 2, 1, 0, 0, Int;
+    Mulu,      0, 2, 1, 0, 0, Int;
+    Div,       0, 2, 1, 0, 0, Int;
+    Divu,      0, 2, 1, 0, 0, Int;
+    Mod,       0, 2, 1, 0, 0, Int;
+    Modu,      0, 2, 1, 0, 0, Int;
+    Neg,       0, 1, 1, 0, 0, Int;
+    Shl,       0, 2, 1, 0, 0, Int;
+    Shlr,      0, 2, 1, 0, 0, Int;
+    Shar,      0, 2, 1, 0, 0, Int;
+    PopCnt,    0, 1, 1, 0, 0, Int;
     // comparaion (i64)
-    Eq,
-    Neq,
-    Lt,
-    Ltu,
-    Leq,
-    Lequ,
-    Gt,
-    Gtu,
-    Geq,
-    Gequ,
+    Eq,        0, 2, 1, 0, 0, Int;
+    Neq,       0, 2, 1, 0, 0, Int;
+    Lt,        0, 2, 1, 0, 0, Int;
+    Ltu,       0, 2, 1, 0, 0, Int;
+    Leq,       0, 2, 1, 0, 0, Int;
+    Lequ,      0, 2, 1, 0, 0, Int;
+    Gt,        0, 2, 1, 0, 0, Int;
+    Gtu,       0, 2, 1, 0, 0, Int;
+    Geq,       0, 2, 1, 0, 0, Int;
+    Gequ,      0, 2, 1, 0, 0, Int;
     // floating (f64)
-    Addf,
-    Subf,
-    Mulf,
-    Divf,
-    Modf,
-    Negf,
-    Invf,
-    Sqrf,
-    Powf,
-    Expf,
-    Logf,
-    Sinf,
-    Cosf,
-    Tanf,
-    ArcSinf,
-    ArcCosf,
-    ArcTanf,
-    Sinhf,
-    Coshf,
-    Tanhf,
-    ArcSinhf,
-    ArcCoshf,
-    ArcTanhf,
+    Addf,      0, 2, 1, 0, 0, Float;
+    Subf,      0, 2, 1, 0, 0, Float;
+    Mulf,      0, 2, 1, 0, 0, Float;
+    Divf,      0, 2, 1, 0, 0, Float;
+    Modf,      0, 2, 1, 0, 0, Float;
+    Negf,      0, 1, 1, 0, 0, Float;
+    Invf,      0, 1, 1, 0, 0, Float;
+    Sqrf,      0, 1, 1, 0, 0, Float;
+    Powf,      0, 2, 1, 0, 0, Float;
+    Expf,      0, 1, 1, 0, 0, Float;
+    Logf,      0, 1, 1, 0, 0, Float;
+    Sinf,      0, 1, 1, 0, 0, Float;
+    Cosf,      0, 1, 1, 0, 0, Float;
+    Tanf,      0, 1, 1, 0, 0, Float;
+    ArcSinf,   0, 1, 1, 0, 0, Float;
+    ArcCosf,   0, 1, 1, 0, 0, Float;
+    ArcTanf,   0, 1, 1, 0, 0, Float;
+    Sinhf,     0, 1, 1, 0, 0, Float;
+    Coshf,     0, 1, 1, 0, 0, Float;
+    Tanhf,     0, 1, 1, 0, 0, Float;
+    ArcSinhf,  0, 1, 1, 0, 0, Float;
+    ArcCoshf,  0, 1, 1, 0, 0, Float;
+    ArcTanhf,  0, 1, 1, 0, 0, Float;
     // comparaion (f64)
-    Eqf,
-    Neqf,
-    Ltf,
-    Leqf,
-    Gtf,
-    Geqf,
+    Eqf,       0, 2, 1, 0, 0, Float;
+    Neqf,      0, 2, 1, 0, 0, Float;
+    Ltf,       0, 2, 1, 0, 0, Float;
+    Leqf,      0, 2, 1, 0, 0, Float;
+    Gtf,       0, 2, 1, 0, 0, Float;
+    Geqf,      0, 2, 1, 0, 0, Float;
     // conversion
-    ItoF,
-    FtoI,
+    ItoF,      0, 1, 1, 0, 0, Conversion;
+    FtoI,      0, 1, 1, 0, 0, Conversion;
+    // subroutine
+    Call,      8, 0, 0, 0, 1, Branch;
+    CallA,     0, 1, 0, 0, 1, Branch;
+    Ret,       0, 0, 0, 1, 0, Branch;
+    // bitwise (i64)
+    And,       0, 2, 1, 0, 0, Int;
+    Or,        0, 2, 1, 0, 0, Int;
+    Xor,       0, 2, 1, 0, 0, Int;
+    Not,       0, 1, 1, 0, 0, Int;
+    Rotl,      0, 2, 1, 0, 0, Int;
+    Rotr,      0, 2, 1, 0, 0, Int;
+    Clz,       0, 1, 1, 0, 0, Int;
+    Ctz,       0, 1, 1, 0, 0, Int;
+    Bswap,     0, 1, 1, 0, 0, Int;
+    // host interface
+    HostCall,  2, 0, 0, 0, 0, Utility;
+    // sign extending immediates and loads
+    Im8s,      1, 0, 1, 0, 0, Stack;
+    Im16s,     2, 0, 1, 0, 0, Stack;
+    Im32s,     4, 0, 1, 0, 0, Stack;
+    Load8s,    0, 1, 1, 0, 0, Memory;
+    Load16s,   0, 1, 1, 0, 0, Memory;
+    Load32s,   0, 1, 1, 0, 0, Memory;
+    LoadData8s, 0, 1, 1, 0, 0, Memory;
+    LoadData16s, 0, 1, 1, 0, 0, Memory;
+    LoadData32s, 0, 1, 1, 0, 0, Memory;
+    // width conversion (i64)
+    Sext8,     0, 1, 1, 0, 0, Conversion;
+    Sext16,    0, 1, 1, 0, 0, Conversion;
+    Sext32,    0, 1, 1, 0, 0, Conversion;
+    Zext8,     0, 1, 1, 0, 0, Conversion;
+    Zext16,    0, 1, 1, 0, 0, Conversion;
+    Zext32,    0, 1, 1, 0, 0, Conversion;
+    // wide and stack operand memory sizing
+    Alloc16,   2, 0, 0, 0, 0, Memory;
+    Alloc32,   4, 0, 0, 0, 0, Memory;
+    Alloc64,   8, 0, 0, 0, 0, Memory;
+    Dealloc16, 2, 0, 0, 0, 0, Memory;
+    Dealloc32, 4, 0, 0, 0, 0, Memory;
+    Dealloc64, 8, 0, 0, 0, 0, Memory;
+    AllocS,    0, 1, 0, 0, 0, Memory;
+    DeallocS,  0, 1, 0, 0, 0, Memory;
+    MemSize,   0, 0, 1, 0, 0, Memory;
+    // heap
+    Malloc,    0, 1, 1, 0, 0, Memory;
+    Free,      0, 1, 0, 0, 0, Memory;
+    Realloc,   0, 2, 1, 0, 0, Memory;
+    // frames and locals
+    Enter,       2, 0, 0, 0, 1, Memory;
+    Leave,       0, 0, 0, 1, 0, Memory;
+    LoadLocal8,  2, 0, 1, 0, 0, Memory;
+    LoadLocal16, 2, 0, 1, 0, 0, Memory;
+    LoadLocal32, 2, 0, 1, 0, 0, Memory;
+    LoadLocal64, 2, 0, 1, 0, 0, Memory;
+    StoreLocal8, 2, 1, 0, 0, 0, Memory;
+    StoreLocal16, 2, 1, 0, 0, 0, Memory;
+    StoreLocal32, 2, 1, 0, 0, 0, Memory;
+    StoreLocal64, 2, 1, 0, 0, 0, Memory;
+    // base plus offset and indexed access
+    Load8Off,   2, 1, 1, 0, 0, Memory;
+    Load16Off,  2, 1, 1, 0, 0, Memory;
+    Load32Off,  2, 1, 1, 0, 0, Memory;
+    Load64Off,  2, 1, 1, 0, 0, Memory;
+    Store8Off,  2, 2, 0, 0, 0, Memory;
+    Store16Off, 2, 2, 0, 0, 0, Memory;
+    Store32Off, 2, 2, 0, 0, 0, Memory;
+    Store64Off, 2, 2, 0, 0, 0, Memory;
+    Load8Idx,   0, 2, 1, 0, 0, Memory;
+    Load16Idx,  0, 2, 1, 0, 0, Memory;
+    Load32Idx,  0, 2, 1, 0, 0, Memory;
+    Load64Idx,  0, 2, 1, 0, 0, Memory;
+    Store8Idx,  0, 3, 0, 0, 0, Memory;
+    Store16Idx, 0, 3, 0, 0, 0, Memory;
+    Store32Idx, 0, 3, 0, 0, 0, Memory;
+    Store64Idx, 0, 3, 0, 0, 0, Memory;
 }
 
 #[allow(dead_code)]
@@ -108,9 +262,71 @@ impl Instructions {
         self.clone() as u8
     }
     pub fn of_opcode(opcode: u8) -> Option<Self> {
-        match Self::try_from_primitive(opcode) {
-            Ok(a) => Some(a),
-            Err(_) => None,
+        Self::try_from_primitive(opcode).ok()
+    }
+    pub fn info(&self) -> &'static InstrInfo {
+        &INFO[self.opcode() as usize]
+    }
+    // every opcode in order
+    pub fn table() -> &'static [InstrInfo] {
+        INFO
+    }
+    // size in bytes of the immediate operand following the opcode
+    pub fn im_size(&self) -> usize {
+        self.info().im_size
+    }
+    // the immediate is a program address to branch to
+    pub fn is_direct_branch(&self) -> bool {
+        let info = self.info();
+        info.category == Category::Branch && info.im_size > 0
+    }
+    // the immediate is sign extended to 64 bits
+    pub fn has_signed_im(&self) -> bool {
+        matches!(self, Self::Im8s | Self::Im16s | Self::Im32s)
+    }
+    pub fn stack_effect(&self) -> StackEffect {
+        self.info().effect
+    }
+    pub fn mnemonic(&self) -> &'static str {
+        self.info().mnemonic
+    }
+    // case insensitive lookup by mnemonic
+    pub fn of_mnemonic(name: &str) -> Option<Self> {
+        INFO.iter()
+            .find(|i| i.mnemonic.eq_ignore_ascii_case(name))
+            .and_then(|i| Self::of_opcode(i.opcode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_instruction_has_info() {
+        let opcodes: Vec<Instructions> =
+            (0..=u8::MAX).filter_map(Instructions::of_opcode).collect();
+        assert_eq!(opcodes.len(), Instructions::table().len());
+        for ins in opcodes {
+            let info = ins.info();
+            assert_eq!(info.opcode, ins.opcode());
+            assert_eq!(info.mnemonic, format!("{ins:?}"));
+            assert_eq!(Instructions::of_mnemonic(info.mnemonic), Some(ins.clone()));
+        }
+    }
+
+    #[test]
+    fn readme_lists_every_instruction() {
+        let readme = include_str!("../../readme.md");
+        for info in Instructions::table() {
+            let item = format!("  - {}", info.mnemonic);
+            assert!(
+                readme
+                    .lines()
+                    .any(|l| l == item || l.starts_with(&format!("{item}:"))),
+                "{} missing from readme",
+                info.mnemonic
+            );
         }
     }
 }
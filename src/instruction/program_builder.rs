@@ -0,0 +1,167 @@
+use std::fmt;
+
+use crate::instruction::program_maker::ProgramMaker;
+use crate::instruction::Instructions;
+
+// branch target created by ProgramBuilder::label
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    UnboundLabel(Label),
+    DoublyBoundLabel(Label),
+    // add_branch with an instruction that takes no address
+    NotABranch(Instructions),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnboundLabel(Label(l)) => write!(f, "label {l} is never bound"),
+            Self::DoublyBoundLabel(Label(l)) => write!(f, "label {l} is bound twice"),
+            Self::NotABranch(ins) => write!(f, "{ins:?} does not take a branch target"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// ProgramMaker with labels, forward references are patched by finish()
+//
+//     let mut b = ProgramBuilder::new();
+//     let end = b.label();
+//     b.add_ins(I::Im8).add_im8(0).add_branch(I::Jz, end);
+//     b.add_ins(I::Interupt).bind(end);
+//     let prog = b.finish()?;
+#[derive(Debug, Default)]
+pub struct ProgramBuilder {
+    code: Vec<u8>,
+    labels: Vec<Option<u64>>,
+    // position of an 8 bytes address slot and its label
+    fixups: Vec<(usize, Label)>,
+    error: Option<BuildError>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // offset the next instruction is emitted at
+    pub fn offset(&self) -> u64 {
+        self.code.len() as u64
+    }
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+    // bind the label to the current offset
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        let offset = self.offset();
+        match &mut self.labels[label.0] {
+            Some(_) => self.fail(BuildError::DoublyBoundLabel(label)),
+            slot => *slot = Some(offset),
+        }
+        self
+    }
+    // emit a branch instruction targeting the label
+    pub fn add_branch(&mut self, ins: Instructions, label: Label) -> &mut Self {
+        if !ins.is_direct_branch() {
+            self.fail(BuildError::NotABranch(ins.clone()));
+        }
+        self.add_ins(ins).add_im_label(label)
+    }
+    // emit the label address as an 8 bytes immediate, e.g. after Im64
+    pub fn add_im_label(&mut self, label: Label) -> &mut Self {
+        self.fixups.push((self.code.len(), label));
+        self.code.add_im64(0);
+        self
+    }
+    pub fn finish(mut self) -> Result<Vec<u8>, BuildError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        for (at, label) in self.fixups {
+            let addr = self.labels[label.0].ok_or(BuildError::UnboundLabel(label))?;
+            self.code[at..at + size_of::<u64>()].copy_from_slice(&addr.to_le_bytes());
+        }
+        Ok(self.code)
+    }
+    // keep the first error for finish()
+    fn fail(&mut self, e: BuildError) {
+        self.error.get_or_insert(e);
+    }
+}
+
+impl ProgramMaker for ProgramBuilder {
+    fn add_ins(&mut self, ins: Instructions) -> &mut Self {
+        self.code.add_ins(ins);
+        self
+    }
+    fn add_im8(&mut self, im: u8) -> &mut Self {
+        self.code.add_im8(im);
+        self
+    }
+    fn add_im16(&mut self, im: u16) -> &mut Self {
+        self.code.add_im16(im);
+        self
+    }
+    fn add_im32(&mut self, im: u32) -> &mut Self {
+        self.code.add_im32(im);
+        self
+    }
+    fn add_im64(&mut self, im: u64) -> &mut Self {
+        self.code.add_im64(im);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instructions as I;
+
+    fn address(code: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(code[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn patches_backward_and_forward_references() {
+        let mut b = ProgramBuilder::new();
+        let (top, end) = (b.label(), b.label());
+        b.bind(top)
+            .add_ins(I::Im8)
+            .add_im8(0)
+            .add_branch(I::Jz, end)
+            .add_branch(I::J, top)
+            .add_ins(I::Im64)
+            .add_im_label(end);
+        assert_eq!(b.offset(), 29);
+        b.bind(end).add_ins(I::Nop);
+        let code = b.finish().unwrap();
+        assert_eq!(code.len(), 30);
+        assert_eq!(code[2], I::Jz.opcode());
+        assert_eq!(address(&code, 3), 29);
+        assert_eq!(code[11], I::J.opcode());
+        assert_eq!(address(&code, 12), 0);
+        assert_eq!(address(&code, 21), 29);
+    }
+
+    #[test]
+    fn reports_the_first_error() {
+        let mut b = ProgramBuilder::new();
+        let l = b.label();
+        b.add_branch(I::Call, l);
+        assert_eq!(b.finish(), Err(BuildError::UnboundLabel(l)));
+
+        let mut b = ProgramBuilder::new();
+        let l = b.label();
+        b.bind(l).add_branch(I::Add, l).bind(l);
+        assert_eq!(b.finish(), Err(BuildError::NotABranch(I::Add)));
+
+        let mut b = ProgramBuilder::new();
+        let l = b.label();
+        b.bind(l).bind(l);
+        assert_eq!(b.finish(), Err(BuildError::DoublyBoundLabel(l)));
+    }
+}
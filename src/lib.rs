@@ -1,2 +1,5 @@
+pub mod assembler;
+pub mod disassembler;
 pub mod instruction;
 pub mod machine;
+pub mod verifier;
@@ -1,58 +1,103 @@
+use crate::machine::trap::Trap;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct CalculationStack {
     raw: Vec<u64>,
+    // maximum number of values
+    max: usize,
 }
 
 #[allow(dead_code)]
 impl CalculationStack {
     pub(crate) fn new() -> CalculationStack {
-        CalculationStack { raw: Vec::new() }
-    }
-    pub(crate) fn push(&mut self, v: u64) {
-        self.raw.push(v)
-    }
-    pub(crate) fn pop(&mut self) -> u64 {
-        self.raw
-            .pop()
-            .expect("pop(?): calculation stack underflowed.")
-    }
-    pub(crate) fn discard(&mut self) {
-        self.raw
-            .pop()
-            .expect("discard(?): calculation stack underflowed.");
-        ()
-    }
-    pub(crate) fn dup(&mut self) {
-        let a = self
-            .raw
-            .last()
-            .expect("dup(?): calculation stack underflowed.");
-        self.raw.push(a.clone())
-    }
-    pub(crate) fn swap(&mut self) {
-        let a = self
-            .raw
-            .pop()
-            .expect("swap(?, ?): calculation stack underflowed.");
-        let b = self
-            .raw
-            .pop()
-            .expect("swap(a, ?): calculation stack underflowed.");
-        self.raw.push(a);
-        self.raw.push(b);
-    }
-    pub(crate) fn over(&mut self) {
-        let a = self
-            .raw
-            .pop()
-            .expect("over(?, ?): calculation stack underflowed.");
-        let b = self
-            .raw
-            .pop()
-            .expect("over(a, ?): calculation stack underflowed.");
-        self.raw.push(b);
-        self.raw.push(a);
-        self.raw.push(b.clone());
+        Self::with_limit(None)
+    }
+    pub(crate) fn with_limit(max: Option<usize>) -> CalculationStack {
+        CalculationStack {
+            raw: Vec::new(),
+            max: max.unwrap_or(usize::MAX),
+        }
+    }
+    fn check_room(&self) -> Result<(), Trap> {
+        match self.raw.len() < self.max {
+            true => Ok(()),
+            false => Err(Trap::CalculationStackOverflow),
+        }
+    }
+    // swap in restored contents, the caller checked them against the limit
+    pub(crate) fn replace(&mut self, raw: Vec<u64>) {
+        self.raw = raw;
+    }
+    pub(crate) fn as_slice(&self) -> &[u64] {
+        &self.raw
+    }
+    pub(crate) fn push(&mut self, v: u64) -> Result<(), Trap> {
+        self.check_room()?;
+        self.raw.push(v);
+        Ok(())
+    }
+    pub(crate) fn pop(&mut self) -> Result<u64, Trap> {
+        self.raw.pop().ok_or(Trap::StackUnderflow)
+    }
+    pub(crate) fn discard(&mut self) -> Result<(), Trap> {
+        self.pop()?;
+        Ok(())
+    }
+    pub(crate) fn dup(&mut self) -> Result<(), Trap> {
+        let a = *self.raw.last().ok_or(Trap::StackUnderflow)?;
+        self.push(a)
+    }
+    pub(crate) fn swap(&mut self) -> Result<(), Trap> {
+        let n = self.raw.len();
+        if n < 2 {
+            return Err(Trap::StackUnderflow);
+        }
+        self.raw.swap(n - 1, n - 2);
+        Ok(())
+    }
+    pub(crate) fn over(&mut self) -> Result<(), Trap> {
+        let n = self.raw.len();
+        if n < 2 {
+            return Err(Trap::StackUnderflow);
+        }
+        self.push(self.raw[n - 2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::test_util::run_with;
+    use crate::machine::{MachineConfig, MachineState};
+
+    #[test]
+    fn push_stops_at_the_limit() {
+        let mut stack = CalculationStack::with_limit(Some(2));
+        stack.push(1).unwrap();
+        stack.dup().unwrap();
+        assert_eq!(stack.push(3), Err(Trap::CalculationStackOverflow));
+        assert_eq!(stack.over(), Err(Trap::CalculationStackOverflow));
+        stack.swap().unwrap();
+        assert_eq!(stack.as_slice(), [1, 1]);
+        stack.discard().unwrap();
+        stack.push(3).unwrap();
+        assert_eq!(stack.as_slice(), [1, 3]);
+    }
+
+    #[test]
+    fn machine_traps_on_overflow() {
+        let config = MachineConfig {
+            max_calculation_stack: Some(4),
+            ..MachineConfig::default()
+        };
+        let mut machine = run_with(config, "top: Im8 1\n J top");
+        assert_eq!(
+            machine.state(),
+            MachineState::Trapped(Trap::CalculationStackOverflow)
+        );
+        assert_eq!(machine.calculation_stack(), [1; 4]);
+        assert_eq!(machine.pc(), 0);
+        assert_eq!(machine.push_arg(1), Err(Trap::CalculationStackOverflow));
     }
 }
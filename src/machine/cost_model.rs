@@ -0,0 +1,177 @@
+use crate::instruction::{Category, Instructions as I};
+
+// fuel charged for each instruction before it runs,
+// the Alloc family, Malloc, Realloc and Enter additionally pay for every byte they allocate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostModel {
+    costs: [u64; 256],
+    alloc_byte: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CostModel {
+    // default costs by category, with slow operations priced higher
+    pub fn new() -> Self {
+        let mut model = Self::uniform(1);
+        for info in I::table() {
+            model.costs[info.opcode as usize] = match info.category {
+                Category::Memory | Category::Branch | Category::Float => 2,
+                _ => 1,
+            };
+        }
+        model
+            .set(I::Div, 4)
+            .set(I::Divu, 4)
+            .set(I::Mod, 4)
+            .set(I::Modu, 4)
+            .set(I::Divf, 4)
+            .set(I::Modf, 4)
+            .set(I::Sqrf, 4)
+            .set(I::HostCall, 20)
+            .set_alloc_byte(1);
+        for ins in [
+            I::Powf,
+            I::Expf,
+            I::Logf,
+            I::Sinf,
+            I::Cosf,
+            I::Tanf,
+            I::ArcSinf,
+            I::ArcCosf,
+            I::ArcTanf,
+            I::Sinhf,
+            I::Coshf,
+            I::Tanhf,
+            I::ArcSinhf,
+            I::ArcCoshf,
+            I::ArcTanhf,
+        ] {
+            model.set(ins, 10);
+        }
+        model
+    }
+    // every instruction costs `cost`, allocation bytes are free
+    pub fn uniform(cost: u64) -> Self {
+        Self {
+            costs: [cost; 256],
+            alloc_byte: 0,
+        }
+    }
+    pub fn set(&mut self, ins: I, cost: u64) -> &mut Self {
+        self.costs[ins.opcode() as usize] = cost;
+        self
+    }
+    pub fn set_alloc_byte(&mut self, cost: u64) -> &mut Self {
+        self.alloc_byte = cost;
+        self
+    }
+    pub fn cost(&self, ins: &I) -> u64 {
+        self.costs[ins.opcode() as usize]
+    }
+    pub fn alloc_byte(&self) -> u64 {
+        self.alloc_byte
+    }
+    // fuel for running `ins`, `size` is the zero extended immediate
+    // or for AllocS, Malloc and Realloc the value on top of the stack
+    pub fn charge(&self, ins: &I, size: u64) -> u64 {
+        let base = self.cost(ins);
+        match ins {
+            I::Alloc
+            | I::Alloc16
+            | I::Alloc32
+            | I::Alloc64
+            | I::AllocS
+            | I::Malloc
+            | I::Realloc
+            | I::Enter => base.saturating_add(size.saturating_mul(self.alloc_byte)),
+            _ => base,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::{Machine, MachineState, Trap};
+
+    #[test]
+    fn default_costs() {
+        let model = CostModel::new();
+        assert_eq!(model.cost(&I::Add), 1);
+        assert_eq!(model.cost(&I::Load64), 2);
+        assert_eq!(model.cost(&I::Jz), 2);
+        assert_eq!(model.cost(&I::Addf), 2);
+        assert_eq!(model.cost(&I::Div), 4);
+        assert_eq!(model.cost(&I::Powf), 10);
+        assert_eq!(model.cost(&I::HostCall), 20);
+        assert_eq!(model.charge(&I::Alloc, 16), 2 + 16);
+        assert_eq!(model.charge(&I::Dealloc, 16), 2);
+        assert_eq!(model.charge(&I::Alloc64, u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn custom_models() {
+        let mut model = CostModel::uniform(3);
+        assert_eq!(model.charge(&I::Alloc, 100), 3);
+        model.set(I::Add, 7).set_alloc_byte(2);
+        assert_eq!(model.cost(&I::Add), 7);
+        assert_eq!(model.charge(&I::Malloc, 5), 13);
+        assert_eq!(model.alloc_byte(), 2);
+    }
+
+    #[test]
+    fn fuel_is_charged_before_running() {
+        let program = assemble("Im8 1\n Im8 2\n Add\n Im8 4\n AllocS").unwrap();
+        let mut m = Machine::new();
+        m.set_cost_model(CostModel::uniform(1));
+        m.set_fuel(Some(3));
+        m.run(&program, None);
+        assert_eq!(m.fuel(), Some(0));
+        assert_eq!(m.pc(), 5);
+        assert_eq!(
+            m.state(),
+            MachineState::Trapped(Trap::OutOfFuel { cost: 1, fuel: 0 })
+        );
+        assert_eq!(m.calculation_stack(), [3]);
+
+        // AllocS pays for the size on top of the stack
+        let mut model = CostModel::uniform(1);
+        model.set_alloc_byte(1);
+        m.set_cost_model(model);
+        m.refuel(4);
+        m.run(&program, None);
+        assert_eq!(
+            m.state(),
+            MachineState::Trapped(Trap::OutOfFuel { cost: 5, fuel: 3 })
+        );
+        assert_eq!(m.pc(), 7);
+        assert!(m.memory().is_empty());
+        m.refuel(2);
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.fuel(), Some(0));
+        assert_eq!(m.memory().len(), 4);
+    }
+
+    #[test]
+    fn unmetered_and_refuel() {
+        let program = assemble("Interupt\n Im8 1").unwrap();
+        let mut m = Machine::new();
+        assert_eq!(m.fuel(), None);
+        m.run(&program, None);
+        // refueling enables metering and keeps other states
+        m.refuel(5);
+        assert_eq!(m.fuel(), Some(5));
+        assert_eq!(m.state(), MachineState::Interupted);
+        m.resume();
+        m.set_fuel(None);
+        m.run(&program, None);
+        assert_eq!((m.state(), m.fuel()), (MachineState::Ended, None));
+    }
+}
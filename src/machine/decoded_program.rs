@@ -0,0 +1,541 @@
+use super::program_memory::ProgramMemory;
+use super::{Machine, MachineState, RunOutcome, StopReason, Trap};
+use crate::instruction::Instructions as I;
+use crate::verifier::{verify, VerifyError};
+
+// Pre-decoded form of a program.
+//
+// Decoding happens once: opcodes become `Op` variants with their immediate
+// inline and direct branch targets resolved to op indices. Hot instructions
+// get their own variant, the rest go through `Machine::step` as `Other`.
+// The byte offset of every op is kept so `Machine::pc` stays exact.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Nop,
+    FromR,
+    ToR,
+    Swap,
+    Over,
+    Dup,
+    Discard,
+    // Im8 .. Im64 and Im8s .. Im32s with the immediate already extended
+    Push(u64),
+    Load64,
+    Store64,
+    // op index of the target
+    J(u32),
+    Jz(u32),
+    Jnz(u32),
+    Call(u32),
+    // Add and Addu, Sub and Subu, Mul and Mulu agree on wrapping u64 bits
+    Add,
+    Sub,
+    Mul,
+    Eq,
+    Neq,
+    Lt,
+    Ltu,
+    Leq,
+    Lequ,
+    Gt,
+    Gtu,
+    Geq,
+    Gequ,
+    And,
+    Or,
+    Xor,
+    Addf,
+    Subf,
+    Mulf,
+    Divf,
+    Ltf,
+    Gtf,
+    ItoF,
+    FtoI,
+    // run through Machine::step with the zero extended immediate
+    Other(I, u64),
+}
+
+impl Op {
+    // immediate as far as fuel charging cares, only Other keeps it
+    fn im(&self) -> u64 {
+        match self {
+            Op::Other(_, im) => *im,
+            _ => 0,
+        }
+    }
+}
+
+const NO_OP: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub struct DecodedProgram<'p> {
+    program: &'p ProgramMemory,
+    ops: Vec<Op>,
+    // the instruction each op was decoded from
+    instructions: Vec<I>,
+    // byte offset of each op, followed by the program length
+    offsets: Vec<u64>,
+    // op index for each byte offset up to and including the program length,
+    // NO_OP inside an instruction
+    index: Vec<u32>,
+}
+
+#[allow(dead_code)]
+impl<'p> DecodedProgram<'p> {
+    // the program is verified first, so every direct branch has an op to land on
+    pub fn new(program: &'p ProgramMemory) -> Result<Self, VerifyError> {
+        let verified = verify(program)?;
+        let ins = verified.instructions();
+        let mut offsets: Vec<u64> = ins.iter().map(|d| d.offset).collect();
+        offsets.push(program.prog_len() as u64);
+        let mut index = vec![NO_OP; program.prog_len() + 1];
+        for (i, o) in offsets.iter().enumerate() {
+            index[*o as usize] = i as u32;
+        }
+        let target = |t: Option<u64>| index[t.unwrap_or(0) as usize];
+        let instructions = ins
+            .iter()
+            .filter_map(|d| d.instruction())
+            .map(|(op, _)| op.clone())
+            .collect();
+        let ops = ins
+            .iter()
+            .filter_map(|d| d.instruction())
+            .map(|(op, im)| match op {
+                I::Nop => Op::Nop,
+                I::FromR => Op::FromR,
+                I::ToR => Op::ToR,
+                I::Swap => Op::Swap,
+                I::Over => Op::Over,
+                I::Dup => Op::Dup,
+                I::Discard => Op::Discard,
+                I::Im8 | I::Im16 | I::Im32 | I::Im64 => Op::Push(im.unwrap_or(0)),
+                I::Im8s => Op::Push(im.unwrap_or(0) as i8 as u64),
+                I::Im16s => Op::Push(im.unwrap_or(0) as i16 as u64),
+                I::Im32s => Op::Push(im.unwrap_or(0) as i32 as u64),
+                I::Load64 => Op::Load64,
+                I::Store64 => Op::Store64,
+                I::J => Op::J(target(im)),
+                I::Jz => Op::Jz(target(im)),
+                I::Jnz => Op::Jnz(target(im)),
+                I::Call => Op::Call(target(im)),
+                I::Add | I::Addu => Op::Add,
+                I::Sub | I::Subu => Op::Sub,
+                I::Mul | I::Mulu => Op::Mul,
+                I::Eq => Op::Eq,
+                I::Neq => Op::Neq,
+                I::Lt => Op::Lt,
+                I::Ltu => Op::Ltu,
+                I::Leq => Op::Leq,
+                I::Lequ => Op::Lequ,
+                I::Gt => Op::Gt,
+                I::Gtu => Op::Gtu,
+                I::Geq => Op::Geq,
+                I::Gequ => Op::Gequ,
+                I::And => Op::And,
+                I::Or => Op::Or,
+                I::Xor => Op::Xor,
+                I::Addf => Op::Addf,
+                I::Subf => Op::Subf,
+                I::Mulf => Op::Mulf,
+                I::Divf => Op::Divf,
+                I::Ltf => Op::Ltf,
+                I::Gtf => Op::Gtf,
+                I::ItoF => Op::ItoF,
+                I::FtoI => Op::FtoI,
+                _ => Op::Other(op.clone(), im.unwrap_or(0)),
+            })
+            .collect();
+        Ok(Self {
+            program,
+            ops,
+            instructions,
+            offsets,
+            index,
+        })
+    }
+    pub fn program(&self) -> &'p ProgramMemory {
+        self.program
+    }
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+    // byte offset of op `i`
+    pub fn offset_of(&self, i: usize) -> u64 {
+        self.offsets[i]
+    }
+    // op index of the instruction starting at byte offset `pc`
+    pub fn index_of(&self, pc: u64) -> Option<usize> {
+        match self.index.get(pc as usize) {
+            Some(&i) if (i as usize) < self.ops.len() => Some(i as usize),
+            _ => None,
+        }
+    }
+}
+
+impl Machine {
+    // same as `run` over a pre-decoded program, the result and the machine
+    // state afterwards match the byte interpreter
+    pub fn run_decoded(&mut self, decoded: &DecodedProgram, limit: Option<u64>) -> RunOutcome {
+        if self.tracer.is_some() {
+            return self.run(decoded.program, limit);
+        }
+        let limit = limit.unwrap_or(u64::MAX);
+        let mut executed = 0;
+        let reason = loop {
+            match &self.state {
+                MachineState::Running => {}
+                MachineState::Interupted => break StopReason::Interupted,
+                MachineState::Ended => break StopReason::Ended,
+                MachineState::Trapped(t) => break StopReason::Trapped(t.clone()),
+            }
+            if executed >= limit {
+                break StopReason::BudgetExhausted;
+            }
+            match decoded.index_of(self.pc) {
+                Some(i) => executed += self.run_ops(decoded, i, limit - executed),
+                // a computed jump into the middle of an instruction
+                // or out of the program, the byte interpreter takes it
+                None => {
+                    if !matches!(
+                        self.run_program(decoded.program),
+                        Err(Trap::OutOfFuel { .. })
+                    ) {
+                        executed += 1;
+                    }
+                }
+            }
+        };
+        RunOutcome { reason, executed }
+    }
+    // run ops from index `i` until the state changes, `limit` ops were run
+    // or pc leaves the op boundaries, returns the number of ops run
+    fn run_ops(&mut self, decoded: &DecodedProgram, mut i: usize, limit: u64) -> u64 {
+        let ops = &decoded.ops;
+        let offsets = &decoded.offsets;
+        let mut executed = 0;
+        while executed < limit {
+            let res = match self.fuel {
+                None => self.op(&ops[i], i, decoded),
+                Some(_) => self
+                    .charge(&decoded.instructions[i], ops[i].im())
+                    .and_then(|_| self.op(&ops[i], i, decoded)),
+            };
+            if !matches!(res, Err(Trap::OutOfFuel { .. })) {
+                executed += 1;
+            }
+            match res {
+                Ok(Some(next)) if next == ops.len() => {
+                    self.pc = offsets[next];
+                    self.state = MachineState::Ended;
+                    return executed;
+                }
+                Ok(Some(next)) => i = next,
+                Ok(None) => {
+                    // pc was set by Machine::step
+                    if self.state != MachineState::Running {
+                        return executed;
+                    }
+                    match decoded.index_of(self.pc) {
+                        Some(next) => i = next,
+                        None => return executed,
+                    }
+                }
+                Err(t) => {
+                    self.pc = offsets[i];
+                    self.state = MachineState::Trapped(t);
+                    return executed;
+                }
+            }
+        }
+        self.pc = offsets[i];
+        executed
+    }
+    // run op `i`, returns the next op index, None when pc is already up to date
+    fn op(&mut self, op: &Op, i: usize, decoded: &DecodedProgram) -> Result<Option<usize>, Trap> {
+        let next = i + 1;
+        match op {
+            // watched accesses need pc and may interrupt, Machine::step handles both
+            Op::Load64 | Op::Store64 if !self.runtime_memory.watches.is_empty() => {
+                self.pc = decoded.offsets[i];
+                self.step(&decoded.instructions[i], 0, decoded.program)?;
+                return Ok(None);
+            }
+            Op::Nop => {}
+            Op::FromR => {
+                let t = self.r_pop()?;
+                self.push(t)?;
+            }
+            Op::ToR => {
+                let t = self.pop()?;
+                self.r_push(t)?;
+            }
+            Op::Swap => self.calculation_stack.swap()?,
+            Op::Over => self.calculation_stack.over()?,
+            Op::Dup => self.calculation_stack.dup()?,
+            Op::Discard => self.calculation_stack.discard()?,
+            Op::Push(v) => self.push(*v)?,
+            Op::Load64 => {
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u64(addr)?;
+                self.push(value)?;
+            }
+            Op::Store64 => {
+                let addr = self.pop()?;
+                let value = self.pop()?;
+                self.runtime_memory.local_save_u64(value, addr)?;
+            }
+            Op::J(t) => return Ok(Some(*t as usize)),
+            Op::Jz(t) => {
+                if self.pop()? == 0 {
+                    return Ok(Some(*t as usize));
+                }
+            }
+            Op::Jnz(t) => {
+                if self.pop()? != 0 {
+                    return Ok(Some(*t as usize));
+                }
+            }
+            Op::Call(t) => {
+                self.r_push(decoded.offsets[next])?;
+                return Ok(Some(*t as usize));
+            }
+            Op::Add => self.binary(|a, b| a.wrapping_add(b))?,
+            Op::Sub => self.binary(|a, b| b.wrapping_sub(a))?,
+            Op::Mul => self.binary(|a, b| a.wrapping_mul(b))?,
+            Op::Eq => self.binary(|a, b| (a == b) as u64)?,
+            Op::Neq => self.binary(|a, b| (a != b) as u64)?,
+            Op::Lt => self.binary(|a, b| ((a as i64) < (b as i64)) as u64)?,
+            Op::Ltu => self.binary(|a, b| (a < b) as u64)?,
+            Op::Leq => self.binary(|a, b| ((a as i64) <= (b as i64)) as u64)?,
+            Op::Lequ => self.binary(|a, b| (a <= b) as u64)?,
+            Op::Gt => self.binary(|a, b| ((a as i64) > (b as i64)) as u64)?,
+            Op::Gtu => self.binary(|a, b| (a > b) as u64)?,
+            Op::Geq => self.binary(|a, b| ((a as i64) >= (b as i64)) as u64)?,
+            Op::Gequ => self.binary(|a, b| (a >= b) as u64)?,
+            Op::And => self.binary(|a, b| a & b)?,
+            Op::Or => self.binary(|a, b| a | b)?,
+            Op::Xor => self.binary(|a, b| a ^ b)?,
+            Op::Addf => self.binary_f64(|a, b| (a + b).to_bits())?,
+            Op::Subf => self.binary_f64(|a, b| (b - a).to_bits())?,
+            Op::Mulf => self.binary_f64(|a, b| (a * b).to_bits())?,
+            Op::Divf => self.binary_f64(|a, b| (a / b).to_bits())?,
+            Op::Ltf => self.binary_f64(|a, b| (a < b) as u64)?,
+            Op::Gtf => self.binary_f64(|a, b| (a > b) as u64)?,
+            Op::ItoF => {
+                let a = self.pop_signed()?;
+                self.push((a as f64).to_bits())?;
+            }
+            Op::FtoI => {
+                let a = f64::from_bits(self.pop()?);
+                self.push_signed(a as i64)?;
+            }
+            Op::Other(ins, im) => {
+                self.pc = decoded.offsets[i];
+                self.step(ins, *im, decoded.program)?;
+                return Ok(None);
+            }
+        }
+        Ok(Some(next))
+    }
+    // `a` is the top of the stack
+    fn binary(&mut self, f: impl Fn(u64, u64) -> u64) -> Result<(), Trap> {
+        let a = self.pop()?;
+        let b = self.pop()?;
+        self.push(f(a, b))?;
+        Ok(())
+    }
+    fn binary_f64(&mut self, f: impl Fn(f64, f64) -> u64) -> Result<(), Trap> {
+        let a = f64::from_bits(self.pop()?);
+        let b = f64::from_bits(self.pop()?);
+        self.push(f(a, b))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::test_util::run;
+    use crate::machine::watch::Watchpoint;
+
+    const SAMPLES: &[&str] = &[
+        // sum 20..1 through memory
+        "       Alloc 8
+                Im8 20
+        loop:   Dup
+                Im8 0
+                Load64
+                Add
+                Im8 0
+                Store64
+                Im8 1
+                Sub
+                Dup
+                Jnz loop
+                Discard
+                Im8 0
+                Load64",
+        // recursive factorial with locals
+        "       Im8 10
+                Call fact
+                J end
+        fact:   Enter 8
+                StoreLocal64 0
+                LoadLocal64 0
+                Jz base
+                LoadLocal64 0
+                Im8 1
+                Sub
+                Call fact
+                LoadLocal64 0
+                Mul
+                Leave
+                Ret
+        base:   Im8 1
+                Leave
+                Ret
+        end:    Im8 7
+                Over
+                Swap
+                Gtu",
+        "       Im64 1.5
+                Im64 2.25
+                Mulf
+                Im64 0.5
+                Subf
+                Dup
+                Sinf
+                Addf
+                Dup
+                FtoI
+                ItoF
+                Ltf
+                Im32s -3
+                Im8 2
+                Mod",
+        "       Im8 32
+                Malloc
+                Dup
+                ToR
+                Im8 7
+                Swap
+                Im8 2
+                Store64Idx
+                FromR
+                Im8 2
+                Load64Idx
+                Im8s -3
+                Sext8
+                Div
+                Im8 0
+                Free",
+        "Im8 1\n Im8 0\n Div",
+        "Im8 1\n Add",
+        "Alloc 4\n Im8 0\n Load64",
+        "Ret",
+        "Im8 1\n Ja\n Nop",
+        "Im8 4\n CallA\n Interupt\n Ret",
+        "Im8 2\n Interupt\n Im8 3\n Mul",
+    ];
+
+    fn machines() -> [Machine; 2] {
+        [Machine::new(), Machine::new()]
+    }
+
+    // runs `setup` machines through the byte interpreter and the decoded ops
+    // and checks they end up the same
+    fn check(src: &str, setup: impl Fn(&mut Machine), limit: Option<u64>) {
+        let program = assemble(src).unwrap();
+        let decoded = DecodedProgram::new(&program).unwrap();
+        let [mut bytes, mut ops] = machines();
+        setup(&mut bytes);
+        setup(&mut ops);
+        let a = bytes.run(&program, limit);
+        let b = ops.run_decoded(&decoded, limit);
+        let context = format!("{src}\nlimit {limit:?}");
+        assert_eq!(a.reason, b.reason, "{context}");
+        assert_eq!(a.executed, b.executed, "{context}");
+        assert_eq!(
+            bytes.snapshot(&program),
+            ops.snapshot(&program),
+            "{context}"
+        );
+        assert_eq!(bytes.watch_events(), ops.watch_events(), "{context}");
+    }
+
+    #[test]
+    fn samples_run_to_the_end() {
+        let expected: [&[u64]; 2] = [&[210], &[3628800, 0]];
+        for (src, stack) in SAMPLES.iter().zip(expected) {
+            let machine = run(src);
+            assert_eq!(machine.state(), MachineState::Ended);
+            assert_eq!(machine.calculation_stack(), stack);
+        }
+    }
+
+    #[test]
+    fn matches_the_byte_interpreter() {
+        for src in SAMPLES {
+            check(src, |_| {}, None);
+            for limit in 0..80 {
+                check(src, |_| {}, Some(limit));
+            }
+        }
+    }
+
+    #[test]
+    fn matches_with_fuel() {
+        for src in SAMPLES {
+            for fuel in 0..150 {
+                check(src, |m| m.set_fuel(Some(fuel)), None);
+                check(src, |m| m.set_fuel(Some(fuel)), Some(fuel / 3));
+            }
+        }
+    }
+
+    #[test]
+    fn matches_after_refuel() {
+        for src in SAMPLES {
+            let program = assemble(src).unwrap();
+            let decoded = DecodedProgram::new(&program).unwrap();
+            let [mut bytes, mut ops] = machines();
+            bytes.set_fuel(Some(5));
+            ops.set_fuel(Some(5));
+            for _ in 0..100 {
+                let a = bytes.run(&program, None);
+                let b = ops.run_decoded(&decoded, None);
+                assert_eq!((a.reason, a.executed), (b.reason, b.executed), "{src}");
+                assert_eq!(bytes.snapshot(&program), ops.snapshot(&program), "{src}");
+                bytes.refuel(5);
+                ops.refuel(5);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_with_watchpoints() {
+        for src in SAMPLES {
+            let watch = |m: &mut Machine| {
+                m.watch(Watchpoint::accesses(0, 8));
+                m.watch(Watchpoint::writes(16, 8).interrupting());
+            };
+            check(src, watch, None);
+            for limit in 0..40 {
+                check(src, watch, Some(limit));
+            }
+        }
+        // an interrupting watch stops both after the Store64
+        let src = "Alloc 8\n Im8 1\n Im8 0\n Store64\n Im8 0\n Load64";
+        check(
+            src,
+            |m| {
+                m.watch(Watchpoint::writes(0, 8).interrupting());
+            },
+            None,
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::machine::runtime_memory::grow_zeroed;
+use crate::machine::trap::Trap;
+
+// Heap region of runtime memory, managed with Malloc, Realloc and Free.
+//
+// Heap addresses start at HEAP_BASE, far above the stack region grown by
+// Alloc, so both share one address space for loads and stores. Blocks are
+// 8 byte aligned and tracked on the Rust side: first fit over an address
+// ordered free list, neighbouring free blocks are merged. New blocks read
+// as zero.
+//
+// In debug mode freed blocks are poisoned and never handed out again, any
+// later access or Free of them traps, and every access has to stay inside
+// one live block.
+pub const HEAP_BASE: u64 = 1 << 48;
+
+// heap offsets stay below this so every HEAP_BASE + offset fits in u64
+const MAX_HEAP: u64 = u64::MAX - HEAP_BASE;
+const ALIGN: u64 = 8;
+const POISON: u8 = 0xdd;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Heap {
+    // byte i lives at HEAP_BASE + i
+    pub(crate) raw: Vec<u8>,
+    // offset, size of live blocks
+    pub(crate) used: BTreeMap<u64, u64>,
+    // offset, size of free blocks, never adjacent to each other
+    pub(crate) free: BTreeMap<u64, u64>,
+    // offset, size of blocks freed in debug mode
+    pub(crate) freed: BTreeMap<u64, u64>,
+    pub(crate) debug: bool,
+}
+
+#[allow(dead_code)]
+impl Heap {
+    // address of a new block of at least `size` bytes,
+    // `room` is how many bytes the heap may still grow
+    pub(crate) fn malloc(&mut self, size: u64, room: u64) -> Result<u64, Trap> {
+        let oom = Trap::OutOfMemory { size };
+        let size = block_size(size).ok_or(oom.clone())?;
+        let fit = self
+            .free
+            .iter()
+            .find(|(_, s)| **s >= size)
+            .map(|(o, s)| (*o, *s));
+        let offset = match fit {
+            Some((o, s)) => {
+                self.free.remove(&o);
+                if s > size {
+                    self.free.insert(o + size, s - size);
+                }
+                o
+            }
+            None => {
+                // grow the heap, starting in a free block at its end
+                let end = self.raw.len() as u64;
+                let (start, tail) = match self.free.last_key_value() {
+                    Some((o, s)) if o + s == end => (*o, *s),
+                    _ => (end, 0),
+                };
+                if size - tail > room {
+                    return Err(oom);
+                }
+                self.grow(start.checked_add(size), oom)?;
+                self.free.remove(&start);
+                start
+            }
+        };
+        self.raw[offset as usize..(offset + size) as usize].fill(0);
+        self.used.insert(offset, size);
+        Ok(HEAP_BASE + offset)
+    }
+
+    pub(crate) fn free(&mut self, addr: u64) -> Result<(), Trap> {
+        let offset = addr.wrapping_sub(HEAP_BASE);
+        let Some(size) = self.used.remove(&offset) else {
+            return Err(self.bad_free(addr));
+        };
+        if self.debug {
+            self.raw[offset as usize..(offset + size) as usize].fill(POISON);
+            self.freed.insert(offset, size);
+        } else {
+            self.release(offset, size);
+        }
+        Ok(())
+    }
+
+    // resize the block at `addr` to `size` bytes keeping its contents,
+    // in place when possible, 0 allocates a new block
+    pub(crate) fn realloc(&mut self, addr: u64, size: u64, room: u64) -> Result<u64, Trap> {
+        if addr == 0 {
+            return self.malloc(size, room);
+        }
+        let offset = addr.wrapping_sub(HEAP_BASE);
+        let Some(&old) = self.used.get(&offset) else {
+            return Err(self.bad_free(addr));
+        };
+        let oom = Trap::OutOfMemory { size };
+        let new = block_size(size).ok_or(oom.clone())?;
+        if new <= old {
+            if new < old {
+                self.used.insert(offset, new);
+                self.release(offset + new, old - new);
+            }
+            return Ok(addr);
+        }
+        let end = offset + old;
+        let following = self.free.get(&end).copied().unwrap_or(0);
+        let at_end = end + following == self.raw.len() as u64;
+        if old + following >= new || (at_end && new - old - following <= room) {
+            if old + following < new {
+                self.grow(offset.checked_add(new), oom)?;
+            }
+            self.free.remove(&end);
+            if old + following > new {
+                self.free.insert(offset + new, old + following - new);
+            }
+            self.raw[end as usize..(offset + new) as usize].fill(0);
+            self.used.insert(offset, new);
+            return Ok(addr);
+        }
+        let to = self.malloc(size, room)?;
+        let to_offset = (to - HEAP_BASE) as usize;
+        self.raw
+            .copy_within(offset as usize..end as usize, to_offset);
+        self.free(addr)?;
+        Ok(to)
+    }
+
+    // byte range of `raw` accessed by `width` bytes at `addr`
+    pub(crate) fn range(&self, addr: u64, width: usize) -> Result<Range<usize>, Trap> {
+        let oob = Trap::MemoryOutOfBounds {
+            addr,
+            width: width as u64,
+        };
+        let offset = addr.wrapping_sub(HEAP_BASE);
+        let end = offset
+            .checked_add(width as u64)
+            .filter(|e| *e <= self.raw.len() as u64)
+            .ok_or(oob.clone())?;
+        if self.debug && !block_covers(&self.used, offset, end) {
+            return Err(match block_covers(&self.freed, offset, offset) {
+                true => Trap::UseAfterFree { addr },
+                false => oob,
+            });
+        }
+        Ok(offset as usize..end as usize)
+    }
+
+    // the used, free and freed blocks are aligned, do not overlap and tile `raw`,
+    // free blocks are never adjacent
+    pub(crate) fn is_consistent(&self) -> bool {
+        let mut blocks: Vec<(u64, u64, bool)> = self
+            .used
+            .iter()
+            .chain(&self.freed)
+            .map(|(o, s)| (*o, *s, false))
+            .chain(self.free.iter().map(|(o, s)| (*o, *s, true)))
+            .collect();
+        blocks.sort_unstable();
+        let mut end = 0;
+        let mut prev_free = false;
+        for (offset, size, free) in blocks {
+            if offset != end || size == 0 || size % ALIGN != 0 || (free && prev_free) {
+                return false;
+            }
+            let Some(e) = offset.checked_add(size) else {
+                return false;
+            };
+            end = e;
+            prev_free = free;
+        }
+        end == self.raw.len() as u64
+    }
+
+    // extend `raw` with zeros to `end`, within MAX_HEAP and what the host can allocate
+    fn grow(&mut self, end: Option<u64>, oom: Trap) -> Result<(), Trap> {
+        let end = end.filter(|e| *e <= MAX_HEAP).ok_or(oom.clone())?;
+        match grow_zeroed(&mut self.raw, end) {
+            true => Ok(()),
+            false => Err(oom),
+        }
+    }
+
+    // put a block back on the free list, merging it with its neighbours
+    fn release(&mut self, mut offset: u64, mut size: u64) {
+        if let Some((&o, &s)) = self.free.range(..offset).next_back() {
+            if o + s == offset {
+                self.free.remove(&o);
+                offset = o;
+                size += s;
+            }
+        }
+        if let Some(s) = self.free.remove(&(offset + size)) {
+            size += s;
+        }
+        self.free.insert(offset, size);
+    }
+
+    fn bad_free(&self, addr: u64) -> Trap {
+        match self.freed.contains_key(&addr.wrapping_sub(HEAP_BASE)) {
+            true => Trap::DoubleFree { addr },
+            false => Trap::InvalidFree { addr },
+        }
+    }
+}
+
+// rounded up to the alignment, at least one unit
+fn block_size(size: u64) -> Option<u64> {
+    size.max(1).checked_next_multiple_of(ALIGN)
+}
+
+// some block in `blocks` contains offsets start..end
+fn block_covers(blocks: &BTreeMap<u64, u64>, start: u64, end: u64) -> bool {
+    blocks
+        .range(..=start)
+        .next_back()
+        .is_some_and(|(o, s)| start < o + s && end <= o + s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::test_util::run;
+    use crate::machine::MachineState;
+
+    const ROOM: u64 = u64::MAX;
+
+    fn debug_heap() -> Heap {
+        Heap {
+            debug: true,
+            ..Heap::default()
+        }
+    }
+
+    #[test]
+    fn malloc_aligns_and_splits_free_blocks() {
+        let mut heap = Heap::default();
+        let a = heap.malloc(1, ROOM).unwrap();
+        let b = heap.malloc(20, ROOM).unwrap();
+        assert_eq!(a, HEAP_BASE);
+        assert_eq!(b, HEAP_BASE + 8);
+        assert_eq!(heap.raw.len(), 32);
+        heap.free(b).unwrap();
+        assert_eq!(heap.free, BTreeMap::from([(8, 24)]));
+        // first fit takes the front of the free block
+        assert_eq!(heap.malloc(8, ROOM).unwrap(), HEAP_BASE + 8);
+        assert_eq!(heap.free, BTreeMap::from([(16, 16)]));
+        assert_eq!(heap.malloc(0, ROOM).unwrap(), HEAP_BASE + 16);
+        assert_eq!(heap.used, BTreeMap::from([(0, 8), (8, 8), (16, 8)]));
+    }
+
+    #[test]
+    fn free_coalesces_neighbours() {
+        let mut heap = Heap::default();
+        let blocks: Vec<u64> = (0..4).map(|_| heap.malloc(8, ROOM).unwrap()).collect();
+        heap.free(blocks[0]).unwrap();
+        heap.free(blocks[2]).unwrap();
+        assert_eq!(heap.free, BTreeMap::from([(0, 8), (16, 8)]));
+        heap.free(blocks[1]).unwrap();
+        assert_eq!(heap.free, BTreeMap::from([(0, 24)]));
+        heap.free(blocks[3]).unwrap();
+        assert_eq!(heap.free, BTreeMap::from([(0, 32)]));
+        assert!(heap.used.is_empty());
+        // the merged block is reused instead of growing
+        assert_eq!(heap.malloc(32, ROOM).unwrap(), HEAP_BASE);
+        assert_eq!(heap.raw.len(), 32);
+    }
+
+    #[test]
+    fn malloc_grows_from_a_free_tail_and_zeroes() {
+        let mut heap = Heap::default();
+        let a = heap.malloc(8, ROOM).unwrap();
+        let b = heap.malloc(8, ROOM).unwrap();
+        heap.raw[8] = 0xff;
+        heap.free(b).unwrap();
+        assert_eq!(heap.malloc(24, ROOM).unwrap(), b);
+        assert_eq!(heap.raw.len(), 32);
+        assert!(heap.raw[8..].iter().all(|b| *b == 0));
+        assert_eq!(heap.malloc(8, 7), Err(Trap::OutOfMemory { size: 8 }));
+        assert_eq!(heap.malloc(8, 8).unwrap(), HEAP_BASE + 32);
+        heap.free(a).unwrap();
+    }
+
+    #[test]
+    fn invalid_and_double_free() {
+        let mut heap = Heap::default();
+        let a = heap.malloc(16, ROOM).unwrap();
+        assert_eq!(heap.free(0), Err(Trap::InvalidFree { addr: 0 }));
+        assert_eq!(heap.free(a + 8), Err(Trap::InvalidFree { addr: a + 8 }));
+        heap.free(a).unwrap();
+        // without debug mode a second free is just an invalid address
+        assert_eq!(heap.free(a), Err(Trap::InvalidFree { addr: a }));
+
+        let mut heap = debug_heap();
+        let a = heap.malloc(16, ROOM).unwrap();
+        heap.free(a).unwrap();
+        assert_eq!(heap.free(a), Err(Trap::DoubleFree { addr: a }));
+        assert_eq!(heap.realloc(a, 8, ROOM), Err(Trap::DoubleFree { addr: a }));
+    }
+
+    #[test]
+    fn realloc_shrinks_and_grows_in_place() {
+        let mut heap = Heap::default();
+        let a = heap.malloc(32, ROOM).unwrap();
+        heap.raw[..4].copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(heap.realloc(a, 8, ROOM).unwrap(), a);
+        assert_eq!(heap.used, BTreeMap::from([(0, 8)]));
+        assert_eq!(heap.free, BTreeMap::from([(8, 24)]));
+        // into the following free block
+        assert_eq!(heap.realloc(a, 16, ROOM).unwrap(), a);
+        assert_eq!(heap.free, BTreeMap::from([(16, 16)]));
+        // past the end of the heap
+        assert_eq!(heap.realloc(a, 48, ROOM).unwrap(), a);
+        assert!(heap.free.is_empty());
+        assert_eq!(heap.raw.len(), 48);
+        assert_eq!(heap.raw[..4], [1, 2, 3, 4]);
+        assert!(heap.raw[8..].iter().all(|b| *b == 0));
+        // address 0 allocates
+        assert_eq!(heap.realloc(0, 8, ROOM).unwrap(), HEAP_BASE + 48);
+    }
+
+    #[test]
+    fn realloc_moves_when_blocked() {
+        let mut heap = Heap::default();
+        let a = heap.malloc(8, ROOM).unwrap();
+        let b = heap.malloc(8, ROOM).unwrap();
+        heap.raw[..8].copy_from_slice(&7u64.to_le_bytes());
+        let moved = heap.realloc(a, 16, ROOM).unwrap();
+        assert_eq!(moved, HEAP_BASE + 16);
+        assert_eq!(heap.raw[16..24], 7u64.to_le_bytes());
+        assert_eq!(heap.used, BTreeMap::from([(8, 8), (16, 16)]));
+        assert_eq!(heap.free, BTreeMap::from([(0, 8)]));
+        assert_eq!(heap.realloc(a, 8, ROOM), Err(Trap::InvalidFree { addr: a }));
+        assert_eq!(heap.realloc(b, 8, 0).unwrap(), b);
+        assert_eq!(heap.realloc(b, 64, 8), Err(Trap::OutOfMemory { size: 64 }));
+    }
+
+    #[test]
+    fn range_checks_bounds() {
+        let mut heap = Heap::default();
+        let a = heap.malloc(8, ROOM).unwrap();
+        assert_eq!(heap.range(a, 8), Ok(0..8));
+        let oob = |addr, width| Trap::MemoryOutOfBounds { addr, width };
+        assert_eq!(heap.range(a + 1, 8), Err(oob(a + 1, 8)));
+        assert_eq!(heap.range(u64::MAX, 1), Err(oob(u64::MAX, 1)));
+    }
+
+    #[test]
+    fn debug_heap_poisons_and_detects_use_after_free() {
+        let mut heap = debug_heap();
+        let a = heap.malloc(8, ROOM).unwrap();
+        let b = heap.malloc(8, ROOM).unwrap();
+        heap.free(a).unwrap();
+        assert!(heap.raw[..8].iter().all(|b| *b == POISON));
+        assert_eq!(
+            heap.range(a + 4, 1),
+            Err(Trap::UseAfterFree { addr: a + 4 })
+        );
+        // freed blocks are quarantined, not reused
+        assert_eq!(heap.malloc(8, ROOM).unwrap(), HEAP_BASE + 16);
+        // accesses must stay inside one live block
+        assert_eq!(heap.range(b, 8), Ok(8..16));
+        assert_eq!(
+            heap.range(b + 4, 8),
+            Err(Trap::MemoryOutOfBounds {
+                addr: b + 4,
+                width: 8
+            })
+        );
+    }
+
+    #[test]
+    fn free_of_zero_is_a_no_op() {
+        assert_eq!(run("Im8 0\n Free").state(), MachineState::Ended);
+        assert_eq!(
+            run("Im8 1\n Free").state(),
+            MachineState::Trapped(Trap::InvalidFree { addr: 1 })
+        );
+    }
+
+    #[test]
+    fn huge_malloc_traps() {
+        let size = 0x8000_0000_0000_0000;
+        assert_eq!(
+            run("Im64 0x8000000000000000\n Malloc").state(),
+            MachineState::Trapped(Trap::OutOfMemory { size })
+        );
+        assert_eq!(
+            run("Im8 8\n Malloc\n Im64 0x8000000000000000\n Realloc").state(),
+            MachineState::Trapped(Trap::OutOfMemory { size })
+        );
+        assert_eq!(
+            run("Im64 -8\n Malloc").state(),
+            MachineState::Trapped(Trap::OutOfMemory { size: u64::MAX - 7 })
+        );
+    }
+}
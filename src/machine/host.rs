@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::machine::calculation_stack::CalculationStack;
+use crate::machine::program_memory::ProgramMemory;
+use crate::machine::runtime_memory::RuntimeMemory;
+use crate::machine::trap::Trap;
+
+pub type HostFunction = Box<dyn Fn(&mut HostContext) -> Result<(), Trap>>;
+
+// host functions callable from bytecode with `HostCall <u16 id>`
+#[derive(Default)]
+pub struct HostFunctions {
+    functions: HashMap<u16, HostFunction>,
+}
+
+impl HostFunctions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // register `f` under `id`, replacing any previous function
+    pub fn register<F>(&mut self, id: u16, f: F) -> &mut Self
+    where
+        F: Fn(&mut HostContext) -> Result<(), Trap> + 'static,
+    {
+        self.functions.insert(id, Box::new(f));
+        self
+    }
+    pub fn unregister(&mut self, id: u16) -> Option<HostFunction> {
+        self.functions.remove(&id)
+    }
+    pub fn contains(&self, id: u16) -> bool {
+        self.functions.contains_key(&id)
+    }
+    pub(crate) fn get(&self, id: u16) -> Option<&HostFunction> {
+        self.functions.get(&id)
+    }
+}
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<_> = self.functions.keys().collect();
+        ids.sort();
+        f.debug_set().entries(ids).finish()
+    }
+}
+
+// what a host function can see of the machine while it runs
+pub struct HostContext<'a> {
+    pub(crate) calculation_stack: &'a mut CalculationStack,
+    pub(crate) runtime_memory: &'a mut RuntimeMemory,
+    pub(crate) program: &'a ProgramMemory,
+    pub(crate) interrupted: bool,
+}
+
+impl HostContext<'_> {
+    pub fn pop(&mut self) -> Result<u64, Trap> {
+        self.calculation_stack.pop()
+    }
+    pub fn pop_signed(&mut self) -> Result<i64, Trap> {
+        Ok(self.pop()? as i64)
+    }
+    pub fn pop_f64(&mut self) -> Result<f64, Trap> {
+        Ok(f64::from_bits(self.pop()?))
+    }
+    pub fn push(&mut self, v: u64) -> Result<(), Trap> {
+        self.calculation_stack.push(v)
+    }
+    pub fn push_signed(&mut self, v: i64) -> Result<(), Trap> {
+        self.push(v as u64)
+    }
+    pub fn push_f64(&mut self, v: f64) -> Result<(), Trap> {
+        self.push(v.to_bits())
+    }
+    pub fn read_u8(&mut self, addr: u64) -> Result<u8, Trap> {
+        self.runtime_memory.local_get_u8(addr)
+    }
+    pub fn read_u16(&mut self, addr: u64) -> Result<u16, Trap> {
+        self.runtime_memory.local_get_u16(addr)
+    }
+    pub fn read_u32(&mut self, addr: u64) -> Result<u32, Trap> {
+        self.runtime_memory.local_get_u32(addr)
+    }
+    pub fn read_u64(&mut self, addr: u64) -> Result<u64, Trap> {
+        self.runtime_memory.local_get_u64(addr)
+    }
+    pub fn read_bytes(&mut self, addr: u64, len: u64) -> Result<&[u8], Trap> {
+        self.runtime_memory.local_get_bytes(addr, len)
+    }
+    pub fn write_u8(&mut self, addr: u64, v: u8) -> Result<(), Trap> {
+        self.runtime_memory.local_save_u8(v, addr)
+    }
+    pub fn write_u16(&mut self, addr: u64, v: u16) -> Result<(), Trap> {
+        self.runtime_memory.local_save_u16(v, addr)
+    }
+    pub fn write_u32(&mut self, addr: u64, v: u32) -> Result<(), Trap> {
+        self.runtime_memory.local_save_u32(v, addr)
+    }
+    pub fn write_u64(&mut self, addr: u64, v: u64) -> Result<(), Trap> {
+        self.runtime_memory.local_save_u64(v, addr)
+    }
+    pub fn write_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), Trap> {
+        self.runtime_memory.local_save_bytes(bytes, addr)
+    }
+    // static data of the running program
+    pub fn data(&self) -> &[u8] {
+        self.program.data()
+    }
+    // stop the machine in MachineState::Interupted after the call returns
+    pub fn interrupt(&mut self) {
+        self.interrupted = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::{Machine, MachineState};
+
+    fn run_hosted(src: &str, register: impl FnOnce(&mut HostFunctions)) -> Machine {
+        let program = assemble(src).unwrap();
+        let mut machine = Machine::new();
+        register(machine.host_functions_mut());
+        machine.run(&program, None);
+        machine
+    }
+
+    #[test]
+    fn closures_pop_arguments_and_push_results() {
+        let m = run_hosted("Im8 7\n Im8s -2\n HostCall 1\n HostCall 2", |h| {
+            h.register(1, |ctx| {
+                let b = ctx.pop_signed()?;
+                let a = ctx.pop_signed()?;
+                ctx.push_signed(a * b)
+            })
+            .register(2, |ctx| {
+                let v = ctx.pop_signed()? as f64;
+                ctx.push_f64(v / 4.0)
+            });
+        });
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.calculation_stack(), [(-3.5f64).to_bits()]);
+    }
+
+    #[test]
+    fn memory_and_data_through_the_context() {
+        let src = "
+            .data
+                .u8 5, 6
+            .code
+                Alloc 16
+                Im16 0x0302
+                Im8 0
+                Store16
+                HostCall 9";
+        let m = run_hosted(src, |h| {
+            h.register(9, |ctx| {
+                let v = ctx.read_u16(0)?;
+                let bytes = ctx.read_bytes(0, 2)?.to_vec();
+                ctx.write_bytes(2, &bytes)?;
+                let d = ctx.data()[1];
+                ctx.write_u32(4, d as u32)?;
+                let high = ctx.read_u8(1)?;
+                ctx.write_u64(8, v as u64 + high as u64)?;
+                // out of bounds accesses trap like the program's own
+                ctx.read_u64(16).map(|_| ())
+            });
+        });
+        assert_eq!(
+            m.state(),
+            MachineState::Trapped(Trap::MemoryOutOfBounds { addr: 16, width: 8 })
+        );
+        assert_eq!(&m.memory()[..8], [2, 3, 2, 3, 6, 0, 0, 0]);
+        assert_eq!(m.memory()[8], 5);
+    }
+
+    #[test]
+    fn interrupt_stops_after_the_call() {
+        let program = assemble("HostCall 3\n Im8 1").unwrap();
+        let mut m = Machine::new();
+        m.host_functions_mut().register(3, |ctx| {
+            ctx.interrupt();
+            Ok(())
+        });
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Interupted);
+        assert_eq!(m.pc(), 3);
+        m.resume();
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.calculation_stack(), [1]);
+    }
+
+    #[test]
+    fn unknown_and_failing_functions_trap_on_the_call() {
+        let m = run_hosted("Nop\n HostCall 0x1234", |_| {});
+        assert_eq!(
+            m.state(),
+            MachineState::Trapped(Trap::UnknownHostFunction { id: 0x1234 })
+        );
+        assert_eq!(m.pc(), 1);
+
+        let m = run_hosted("Im8 1\n HostCall 4\n Nop", |h| {
+            h.register(4, |_| Err(Trap::Host("refused".to_string())));
+            assert!(h.contains(4));
+        });
+        assert_eq!(
+            m.state(),
+            MachineState::Trapped(Trap::Host("refused".to_string()))
+        );
+        assert_eq!(m.pc(), 2);
+        assert_eq!(m.calculation_stack(), [1]);
+
+        let mut functions = HostFunctions::new();
+        functions.register(5, |_| Ok(())).register(2, |_| Ok(()));
+        assert_eq!(format!("{functions:?}"), "{2, 5}");
+        assert!(functions.unregister(5).is_some());
+        assert!(!functions.contains(5));
+    }
+}
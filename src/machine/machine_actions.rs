@@ -1,403 +1,1284 @@
+use super::host::HostContext;
 use super::program_memory::ProgramMemory;
 use super::Machine;
 use super::MachineState;
+use super::Trap;
+use super::{RunOutcome, StopReason};
 use crate::instruction::Instructions as I;
 
 impl Machine {
-    pub fn run_program(&mut self, program: &ProgramMemory) {
-        let instruct = program.get_opcode_at(self.pc);
+    // fetch instruction, run it and increase pc,
+    // a trap moves the machine into MachineState::Trapped
+    pub fn run_program(&mut self, program: &ProgramMemory) -> Result<(), Trap> {
+        if let MachineState::Trapped(t) = &self.state {
+            return Err(t.clone());
+        }
+        let res = match self.tracer {
+            Some(_) => self.execute_traced(program),
+            None => self.execute(program),
+        };
+        if let Err(t) = res {
+            self.state = MachineState::Trapped(t.clone());
+            return Err(t);
+        }
+        Ok(())
+    }
+    // run until the machine stops running or `limit` instructions were executed
+    pub fn run(&mut self, program: &ProgramMemory, limit: Option<u64>) -> RunOutcome {
+        let mut executed = 0;
+        let reason = loop {
+            match &self.state {
+                MachineState::Running => {}
+                MachineState::Interupted => break StopReason::Interupted,
+                MachineState::Ended => break StopReason::Ended,
+                MachineState::Trapped(t) => break StopReason::Trapped(t.clone()),
+            }
+            if limit.is_some_and(|l| executed >= l) {
+                break StopReason::BudgetExhausted;
+            }
+            // a trap is recorded in the state and reported on the next iteration,
+            // an instruction stopped by OutOfFuel did not run
+            if !matches!(self.run_program(program), Err(Trap::OutOfFuel { .. })) {
+                executed += 1;
+            }
+        };
+        RunOutcome { reason, executed }
+    }
+    pub(crate) fn execute(&mut self, program: &ProgramMemory) -> Result<(), Trap> {
+        let instruct = program.get_opcode_at(self.pc)?;
+        let im = program.get_im_at(self.pc + 1, instruct.im_size())?;
+        self.charge(&instruct, im)?;
+        self.step(&instruct, im, program)
+    }
+    // run one fetched instruction, `im` is its zero extended immediate operand
+    pub(crate) fn step(
+        &mut self,
+        instruct: &I,
+        im: u64,
+        program: &ProgramMemory,
+    ) -> Result<(), Trap> {
+        self.runtime_memory.watches.pc = self.pc;
         match instruct {
             I::Nop => {}
             I::Interupt => self.state = MachineState::Interupted,
             I::FromR => {
-                let t = self.r_pop();
-                self.push(t);
+                let t = self.r_pop()?;
+                self.push(t)?;
             }
             I::ToR => {
-                let t = self.pop();
-                self.r_push(t);
+                let t = self.pop()?;
+                self.r_push(t)?;
             }
-            I::Swap => self.calculation_stack.swap(),
-            I::Over => self.calculation_stack.over(),
-            I::Dup => self.calculation_stack.dup(),
-            I::Discard => self.calculation_stack.discard(),
+            I::Swap => self.calculation_stack.swap()?,
+            I::Over => self.calculation_stack.over()?,
+            I::Dup => self.calculation_stack.dup()?,
+            I::Discard => self.calculation_stack.discard()?,
             I::Im8 => {
-                let im = program.get_im_u8_at(self.pc + 1);
-                self.push(im as u64);
+                self.push(im)?;
                 self.skip_im(size_of::<u8>()); // pc + 8
             }
             I::Im16 => {
-                let im = program.get_im_u16_at(self.pc + 1);
-                self.push(im as u64);
+                self.push(im)?;
                 self.skip_im(size_of::<u16>()); // pc + 8
             }
             I::Im32 => {
-                let im = program.get_im_u32_at(self.pc + 1);
-                self.push(im as u64);
+                self.push(im)?;
                 self.skip_im(size_of::<u32>()); // pc + 8
             }
             I::Im64 => {
-                let im = program.get_im_u64_at(self.pc + 1);
-                self.push(im);
+                self.push(im)?;
                 self.skip_im(size_of::<u64>()); // pc + 8
             }
             I::Store8 => {
-                let addr = self.pop();
-                let value = self.pop();
-                self.runtime_memory.local_save_u8(value as u8, addr);
+                let addr = self.pop()?;
+                let value = self.pop()?;
+                self.runtime_memory.local_save_u8(value as u8, addr)?;
             }
             I::Load8 => {
-                let addr = self.pop();
-                let value = self.runtime_memory.local_get_u8(addr);
-                self.push(value as u64);
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u8(addr)?;
+                self.push(value as u64)?;
             }
             I::Store16 => {
-                let addr = self.pop();
-                let value = self.pop();
-                self.runtime_memory.local_save_u16(value as u16, addr);
+                let addr = self.pop()?;
+                let value = self.pop()?;
+                self.runtime_memory.local_save_u16(value as u16, addr)?;
             }
             I::Load16 => {
-                let addr = self.pop();
-                let value = self.runtime_memory.local_get_u16(addr);
-                self.push(value as u64);
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u16(addr)?;
+                self.push(value as u64)?;
             }
             I::Store32 => {
-                let addr = self.pop();
-                let value = self.pop();
-                self.runtime_memory.local_save_u32(value as u32, addr);
+                let addr = self.pop()?;
+                let value = self.pop()?;
+                self.runtime_memory.local_save_u32(value as u32, addr)?;
             }
             I::Load32 => {
-                let addr = self.pop();
-                let value = self.runtime_memory.local_get_u32(addr);
-                self.push(value as u64);
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u32(addr)?;
+                self.push(value as u64)?;
             }
             I::Store64 => {
-                let addr = self.pop();
-                let value = self.pop();
-                self.runtime_memory.local_save_u64(value, addr);
+                let addr = self.pop()?;
+                let value = self.pop()?;
+                self.runtime_memory.local_save_u64(value, addr)?;
             }
             I::Load64 => {
-                let addr = self.pop();
-                let value = self.runtime_memory.local_get_u64(addr);
-                self.push(value);
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u64(addr)?;
+                self.push(value)?;
             }
             I::Alloc => {
-                let siz = program.get_im_u8_at(self.pc + 1);
-                self.runtime_memory.alloc(siz as u64);
+                self.runtime_memory.alloc(im)?;
                 self.skip_im(size_of::<u8>());
             }
             I::Dealloc => {
-                let siz = program.get_im_u8_at(self.pc + 1);
-                self.runtime_memory.dealloc(siz as u64);
+                self.runtime_memory.dealloc(im)?;
                 self.skip_im(size_of::<u8>());
             }
             I::LoadData8 => {
-                let addr = self.pop();
-                let value = program.get_data_u8(addr);
-                self.push(value as u64);
+                let addr = self.pop()?;
+                let value = program.get_data_u8(addr)?;
+                self.push(value as u64)?;
             }
             I::LoadData16 => {
-                let addr = self.pop();
-                let value = program.get_data_u16(addr);
-                self.push(value as u64);
+                let addr = self.pop()?;
+                let value = program.get_data_u16(addr)?;
+                self.push(value as u64)?;
             }
             I::LoadData32 => {
-                let addr = self.pop();
-                let value = program.get_data_u32(addr);
-                self.push(value as u64);
+                let addr = self.pop()?;
+                let value = program.get_data_u32(addr)?;
+                self.push(value as u64)?;
             }
             I::LoadData64 => {
-                let addr = self.pop();
-                let value = program.get_data_u64_at(addr);
-                self.push(value);
+                let addr = self.pop()?;
+                let value = program.get_data_u64(addr)?;
+                self.push(value)?;
             }
             I::J => {
-                let addr = program.get_im_u64_at(self.pc + 1);
-                self.pc = addr - 1; // -1 for later increase
+                let addr = im;
+                self.jump(addr);
             }
             I::Jz => {
-                let addr = program.get_im_u64_at(self.pc + 1);
-                let a = self.pop();
-                match a {
-                    0 => self.pc = addr - 1,
-                    _ => (),
+                let addr = im;
+                let a = self.pop()?;
+                if a == 0 {
+                    self.jump(addr);
+                } else {
+                    self.skip_im(size_of::<u64>());
                 }
             }
             I::Jnz => {
-                let addr = program.get_im_u64_at(self.pc + 1);
-                let a = self.pop();
-                match a {
-                    0 => (),
-                    _ => self.pc = addr - 1,
+                let addr = im;
+                let a = self.pop()?;
+                if a != 0 {
+                    self.jump(addr);
+                } else {
+                    self.skip_im(size_of::<u64>());
                 }
             }
             I::Ja => {
-                let addr = self.pop();
-                self.pc = addr - 1;
+                let addr = self.pop()?;
+                self.jump(addr);
             }
             I::Add => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push_signed(a + b);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                self.push_signed(a.wrapping_add(b))?;
             }
             I::Addu => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push(a + b);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a.wrapping_add(b))?;
             }
             I::Sub => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push_signed(b - a);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                self.push_signed(b.wrapping_sub(a))?;
             }
             I::Subu => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push(b - a);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(b.wrapping_sub(a))?;
             }
             I::Mul => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push_signed(a * b);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                self.push_signed(a.wrapping_mul(b))?;
             }
             I::Mulu => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push(a * b);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a.wrapping_mul(b))?;
             }
             I::Div => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push_signed(a / b);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                if b == 0 {
+                    return Err(Trap::DivideByZero);
+                }
+                self.push_signed(a.wrapping_div(b))?;
             }
             I::Divu => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push(a / b);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                if b == 0 {
+                    return Err(Trap::DivideByZero);
+                }
+                self.push(a / b)?;
             }
             I::Mod => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push_signed(a % b);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                if b == 0 {
+                    return Err(Trap::DivideByZero);
+                }
+                self.push_signed(a.wrapping_rem(b))?;
             }
             I::Modu => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push(a % b);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                if b == 0 {
+                    return Err(Trap::DivideByZero);
+                }
+                self.push(a % b)?;
             }
             I::Neg => {
-                let t = self.pop_signed();
-                self.push_signed(-t);
+                let t = self.pop_signed()?;
+                self.push_signed(t.wrapping_neg())?;
             }
             I::Shl => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push(a << b);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a.wrapping_shl(b as u32))?;
             }
             I::Shlr => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push(a >> b);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a.wrapping_shr(b as u32))?;
             }
             I::Shar => {
-                let a = self.pop_signed();
-                let b = self.pop();
-                self.push_signed(a >> b);
+                let a = self.pop_signed()?;
+                let b = self.pop()?;
+                self.push_signed(a.wrapping_shr(b as u32))?;
             }
             I::PopCnt => {
-                let t = self.pop();
-                self.push(t.count_ones() as u64);
+                let t = self.pop()?;
+                self.push(t.count_ones() as u64)?;
             }
             I::Eq => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push((a == b) as u64);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((a == b) as u64)?;
             }
             I::Neq => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push((a != b) as u64);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((a != b) as u64)?;
             }
             I::Lt => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push((a < b) as u64);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                self.push((a < b) as u64)?;
             }
             I::Ltu => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push((a < b) as u64);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((a < b) as u64)?;
             }
             I::Leq => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push((a <= b) as u64);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                self.push((a <= b) as u64)?;
             }
             I::Lequ => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push((a <= b) as u64);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((a <= b) as u64)?;
             }
             I::Gt => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push((a > b) as u64);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                self.push((a > b) as u64)?;
             }
             I::Gtu => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push((a > b) as u64);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((a > b) as u64)?;
             }
             I::Geq => {
-                let a = self.pop_signed();
-                let b = self.pop_signed();
-                self.push((a >= b) as u64);
+                let a = self.pop_signed()?;
+                let b = self.pop_signed()?;
+                self.push((a >= b) as u64)?;
             }
             I::Gequ => {
-                let a = self.pop();
-                let b = self.pop();
-                self.push((a >= b) as u64);
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push((a >= b) as u64)?;
             }
             I::Addf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a + b).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a + b).to_bits())?;
             }
             I::Subf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((b - a).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((b - a).to_bits())?;
             }
             I::Mulf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a * b).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a * b).to_bits())?;
             }
             I::Divf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a / b).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a / b).to_bits())?;
             }
             I::Modf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a % b).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a % b).to_bits())?;
             }
             I::Negf => {
-                let a = f64::from_bits(self.pop());
-                self.push((-a).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push((-a).to_bits())?;
             }
             I::Invf => {
-                let a = f64::from_bits(self.pop());
-                self.push((1. / a).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push((1. / a).to_bits())?;
             }
             I::Sqrf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.sqrt().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.sqrt().to_bits())?;
             }
             I::Powf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push(a.powf(b).to_bits());
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push(a.powf(b).to_bits())?;
             }
             I::Expf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.exp().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.exp().to_bits())?;
             }
             I::Logf => {
-                let a = f64::from_bits(self.pop());
-                self.push((a - 1.).ln_1p().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push((a - 1.).ln_1p().to_bits())?;
             }
             I::Sinf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.sin().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.sin().to_bits())?;
             }
             I::Cosf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.cos().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.cos().to_bits())?;
             }
             I::Tanf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.tan().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.tan().to_bits())?;
             }
             I::ArcSinf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.asin().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.asin().to_bits())?;
             }
             I::ArcCosf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.acos().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.acos().to_bits())?;
             }
             I::ArcTanf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.atan().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.atan().to_bits())?;
             }
             I::Eqf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a == b) as u64);
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a == b) as u64)?;
             }
             I::Neqf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a != b) as u64);
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a != b) as u64)?;
             }
             I::Ltf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a < b) as u64);
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a < b) as u64)?;
             }
             I::Leqf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a <= b) as u64);
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a <= b) as u64)?;
             }
             I::Gtf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a > b) as u64);
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a > b) as u64)?;
             }
             I::Geqf => {
-                let a = f64::from_bits(self.pop());
-                let b = f64::from_bits(self.pop());
-                self.push((a >= b) as u64);
+                let a = f64::from_bits(self.pop()?);
+                let b = f64::from_bits(self.pop()?);
+                self.push((a >= b) as u64)?;
             }
             I::ItoF => {
-                let a = self.pop() as i64;
-                self.push((a as f64).to_bits());
+                let a = self.pop_signed()?;
+                self.push((a as f64).to_bits())?;
             }
             I::FtoI => {
-                let a = f64::from_bits(self.pop());
-                self.push(u64::from_le_bytes((a as i64).to_le_bytes()));
+                let a = f64::from_bits(self.pop()?);
+                self.push_signed(a as i64)?;
             }
             I::Sinhf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.sinh().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.sinh().to_bits())?;
             }
             I::Coshf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.cosh().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.cosh().to_bits())?;
             }
             I::Tanhf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.tanh().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.tanh().to_bits())?;
             }
             I::ArcSinhf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.asinh().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.asinh().to_bits())?;
             }
             I::ArcCoshf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.acosh().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.acosh().to_bits())?;
             }
             I::ArcTanhf => {
-                let a = f64::from_bits(self.pop());
-                self.push(a.atanh().to_bits());
+                let a = f64::from_bits(self.pop()?);
+                self.push(a.atanh().to_bits())?;
+            }
+            I::Call => {
+                let addr = im;
+                self.r_push(self.pc + 1 + size_of::<u64>() as u64)?;
+                self.jump(addr);
+            }
+            I::CallA => {
+                let addr = self.pop()?;
+                self.r_push(self.pc + 1)?;
+                self.jump(addr);
+            }
+            I::Ret => {
+                let addr = self.r_pop()?;
+                self.jump(addr);
+            }
+            I::And => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a & b)?;
+            }
+            I::Or => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a | b)?;
+            }
+            I::Xor => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a ^ b)?;
+            }
+            I::Not => {
+                let t = self.pop()?;
+                self.push(!t)?;
+            }
+            I::Rotl => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a.rotate_left(b as u32))?;
+            }
+            I::Rotr => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(a.rotate_right(b as u32))?;
+            }
+            I::Clz => {
+                let t = self.pop()?;
+                self.push(t.leading_zeros() as u64)?;
+            }
+            I::Ctz => {
+                let t = self.pop()?;
+                self.push(t.trailing_zeros() as u64)?;
+            }
+            I::Bswap => {
+                let t = self.pop()?;
+                self.push(t.swap_bytes())?;
+            }
+            I::HostCall => {
+                let id = im as u16;
+                let f = self
+                    .host_functions
+                    .get(id)
+                    .ok_or(Trap::UnknownHostFunction { id })?;
+                let mut ctx = HostContext {
+                    calculation_stack: &mut self.calculation_stack,
+                    runtime_memory: &mut self.runtime_memory,
+                    program,
+                    interrupted: false,
+                };
+                f(&mut ctx)?;
+                if ctx.interrupted {
+                    self.state = MachineState::Interupted;
+                }
+                self.skip_im(size_of::<u16>());
+            }
+            I::Im8s => {
+                self.push_signed(im as i8 as i64)?;
+                self.skip_im(size_of::<i8>());
+            }
+            I::Im16s => {
+                self.push_signed(im as i16 as i64)?;
+                self.skip_im(size_of::<i16>());
+            }
+            I::Im32s => {
+                self.push_signed(im as i32 as i64)?;
+                self.skip_im(size_of::<i32>());
+            }
+            I::Load8s => {
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u8(addr)? as i8;
+                self.push_signed(value as i64)?;
+            }
+            I::Load16s => {
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u16(addr)? as i16;
+                self.push_signed(value as i64)?;
+            }
+            I::Load32s => {
+                let addr = self.pop()?;
+                let value = self.runtime_memory.local_get_u32(addr)? as i32;
+                self.push_signed(value as i64)?;
+            }
+            I::LoadData8s => {
+                let addr = self.pop()?;
+                let value = program.get_data_u8(addr)? as i8;
+                self.push_signed(value as i64)?;
+            }
+            I::LoadData16s => {
+                let addr = self.pop()?;
+                let value = program.get_data_u16(addr)? as i16;
+                self.push_signed(value as i64)?;
+            }
+            I::LoadData32s => {
+                let addr = self.pop()?;
+                let value = program.get_data_u32(addr)? as i32;
+                self.push_signed(value as i64)?;
+            }
+            I::Sext8 => {
+                let t = self.pop()?;
+                self.push_signed(t as i8 as i64)?;
+            }
+            I::Sext16 => {
+                let t = self.pop()?;
+                self.push_signed(t as i16 as i64)?;
+            }
+            I::Sext32 => {
+                let t = self.pop()?;
+                self.push_signed(t as i32 as i64)?;
+            }
+            I::Zext8 => {
+                let t = self.pop()?;
+                self.push(t as u8 as u64)?;
+            }
+            I::Zext16 => {
+                let t = self.pop()?;
+                self.push(t as u16 as u64)?;
+            }
+            I::Zext32 => {
+                let t = self.pop()?;
+                self.push(t as u32 as u64)?;
+            }
+            I::Alloc16 => {
+                self.runtime_memory.alloc(im)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Alloc32 => {
+                self.runtime_memory.alloc(im)?;
+                self.skip_im(size_of::<u32>());
+            }
+            I::Alloc64 => {
+                self.runtime_memory.alloc(im)?;
+                self.skip_im(size_of::<u64>());
+            }
+            I::Dealloc16 => {
+                self.runtime_memory.dealloc(im)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Dealloc32 => {
+                self.runtime_memory.dealloc(im)?;
+                self.skip_im(size_of::<u32>());
+            }
+            I::Dealloc64 => {
+                self.runtime_memory.dealloc(im)?;
+                self.skip_im(size_of::<u64>());
+            }
+            I::AllocS => {
+                let size = self.pop()?;
+                self.runtime_memory.alloc(size)?;
+            }
+            I::DeallocS => {
+                let size = self.pop()?;
+                self.runtime_memory.dealloc(size)?;
+            }
+            I::MemSize => {
+                let size = self.runtime_memory.as_slice().len();
+                self.push(size as u64)?;
+            }
+            I::Malloc => {
+                let size = self.pop()?;
+                let addr = self.runtime_memory.malloc(size)?;
+                self.push(addr)?;
+            }
+            I::Free => {
+                let addr = self.pop()?;
+                if addr != 0 {
+                    self.runtime_memory.heap.free(addr)?;
+                }
+            }
+            I::Realloc => {
+                let size = self.pop()?;
+                let addr = self.pop()?;
+                let addr = self.runtime_memory.realloc(addr, size)?;
+                self.push(addr)?;
+            }
+            I::Enter => {
+                // the frame starts at the current end of runtime memory
+                let fp = self.runtime_memory.as_slice().len() as u64;
+                self.r_push(self.fp)?;
+                if let Err(t) = self.runtime_memory.alloc(im) {
+                    self.r_pop()?;
+                    return Err(t);
+                }
+                self.fp = fp;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Leave => {
+                let len = self.runtime_memory.as_slice().len() as u64;
+                if self.fp > len {
+                    return Err(Trap::MemoryUnderflow {
+                        size: self.fp - len,
+                    });
+                }
+                let fp = self.r_pop()?;
+                self.runtime_memory.dealloc(len - self.fp)?;
+                self.fp = fp;
+            }
+            I::LoadLocal8 => {
+                let addr = Self::address(self.fp, im, 1)?;
+                let value = self.runtime_memory.local_get_u8(addr)?;
+                self.push(value as u64)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::LoadLocal16 => {
+                let addr = Self::address(self.fp, im, 2)?;
+                let value = self.runtime_memory.local_get_u16(addr)?;
+                self.push(value as u64)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::LoadLocal32 => {
+                let addr = Self::address(self.fp, im, 4)?;
+                let value = self.runtime_memory.local_get_u32(addr)?;
+                self.push(value as u64)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::LoadLocal64 => {
+                let addr = Self::address(self.fp, im, 8)?;
+                let value = self.runtime_memory.local_get_u64(addr)?;
+                self.push(value)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::StoreLocal8 => {
+                let value = self.pop()?;
+                let addr = Self::address(self.fp, im, 1)?;
+                self.runtime_memory.local_save_u8(value as u8, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::StoreLocal16 => {
+                let value = self.pop()?;
+                let addr = Self::address(self.fp, im, 2)?;
+                self.runtime_memory.local_save_u16(value as u16, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::StoreLocal32 => {
+                let value = self.pop()?;
+                let addr = Self::address(self.fp, im, 4)?;
+                self.runtime_memory.local_save_u32(value as u32, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::StoreLocal64 => {
+                let value = self.pop()?;
+                let addr = Self::address(self.fp, im, 8)?;
+                self.runtime_memory.local_save_u64(value, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Load8Off => {
+                let base = self.pop()?;
+                let addr = Self::address(base, im, 1)?;
+                let value = self.runtime_memory.local_get_u8(addr)?;
+                self.push(value as u64)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Load16Off => {
+                let base = self.pop()?;
+                let addr = Self::address(base, im, 2)?;
+                let value = self.runtime_memory.local_get_u16(addr)?;
+                self.push(value as u64)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Load32Off => {
+                let base = self.pop()?;
+                let addr = Self::address(base, im, 4)?;
+                let value = self.runtime_memory.local_get_u32(addr)?;
+                self.push(value as u64)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Load64Off => {
+                let base = self.pop()?;
+                let addr = Self::address(base, im, 8)?;
+                let value = self.runtime_memory.local_get_u64(addr)?;
+                self.push(value)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Store8Off => {
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::address(base, im, 1)?;
+                self.runtime_memory.local_save_u8(value as u8, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Store16Off => {
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::address(base, im, 2)?;
+                self.runtime_memory.local_save_u16(value as u16, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Store32Off => {
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::address(base, im, 4)?;
+                self.runtime_memory.local_save_u32(value as u32, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Store64Off => {
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::address(base, im, 8)?;
+                self.runtime_memory.local_save_u64(value, addr)?;
+                self.skip_im(size_of::<u16>());
+            }
+            I::Load8Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let addr = Self::indexed(base, index, 1)?;
+                let value = self.runtime_memory.local_get_u8(addr)?;
+                self.push(value as u64)?;
+            }
+            I::Load16Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let addr = Self::indexed(base, index, 2)?;
+                let value = self.runtime_memory.local_get_u16(addr)?;
+                self.push(value as u64)?;
+            }
+            I::Load32Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let addr = Self::indexed(base, index, 4)?;
+                let value = self.runtime_memory.local_get_u32(addr)?;
+                self.push(value as u64)?;
+            }
+            I::Load64Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let addr = Self::indexed(base, index, 8)?;
+                let value = self.runtime_memory.local_get_u64(addr)?;
+                self.push(value)?;
+            }
+            I::Store8Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::indexed(base, index, 1)?;
+                self.runtime_memory.local_save_u8(value as u8, addr)?;
+            }
+            I::Store16Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::indexed(base, index, 2)?;
+                self.runtime_memory.local_save_u16(value as u16, addr)?;
+            }
+            I::Store32Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::indexed(base, index, 4)?;
+                self.runtime_memory.local_save_u32(value as u32, addr)?;
+            }
+            I::Store64Idx => {
+                let index = self.pop()?;
+                let base = self.pop()?;
+                let value = self.pop()?;
+                let addr = Self::indexed(base, index, 8)?;
+                self.runtime_memory.local_save_u64(value, addr)?;
             }
         };
+        if self.runtime_memory.watches.take_interrupt() {
+            self.state = MachineState::Interupted;
+        }
         self.next();
         if self.pc >= program.prog_len() as u64 {
             self.state = MachineState::Ended
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::machine::test_util::{run, run_with};
+    use crate::machine::{Machine, MachineConfig, MachineState, RunOutcome, StopReason, Trap};
+
+    #[test]
+    fn frames_hold_locals() {
+        let m = run("
+            Alloc 4
+            Enter 16
+            Im16 0x1234
+            StoreLocal16 14
+            LoadLocal8 15
+            Im8 9
+            StoreLocal64 0
+            Enter 8
+            Im8 5
+            StoreLocal32 4
+            LoadLocal32 4
+            Leave
+            LoadLocal64 0");
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.calculation_stack(), [0x12, 5, 9]);
+        assert_eq!(m.fp(), 4);
+        assert_eq!(m.return_stack(), [0]);
+        assert_eq!(m.memory().len(), 20);
+        assert_eq!(m.memory()[4..12], 9u64.to_le_bytes());
+        assert_eq!(m.memory()[18..], [0x34, 0x12]);
+    }
+
+    #[test]
+    fn leave_then_ret_returns() {
+        let m = run("
+            Im8 4
+            Call double
+            J end
+        double: Enter 8
+            StoreLocal64 0
+            LoadLocal64 0
+            LoadLocal64 0
+            Add
+            Leave
+            Ret
+        end: MemSize");
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.calculation_stack(), [8, 0]);
+        assert!(m.return_stack().is_empty());
+    }
+
+    #[test]
+    fn locals_are_bounds_checked() {
+        let oob = |addr, width| MachineState::Trapped(Trap::MemoryOutOfBounds { addr, width });
+        assert_eq!(run("Enter 8\n LoadLocal64 1").state(), oob(1, 8));
+        assert_eq!(
+            run("Alloc 8\n Enter 2\n Im8 1\n StoreLocal16 1").state(),
+            oob(9, 2)
+        );
+        assert_eq!(
+            run("Leave").state(),
+            MachineState::Trapped(Trap::ReturnStackUnderflow)
+        );
+        assert_eq!(
+            run("Alloc 8\n Enter 8\n Dealloc 12\n Leave").state(),
+            MachineState::Trapped(Trap::MemoryUnderflow { size: 4 })
+        );
+    }
+
+    #[test]
+    fn failed_enter_changes_nothing() {
+        let m = run_with(
+            MachineConfig {
+                max_return_stack: Some(1),
+                ..Default::default()
+            },
+            "Im8 1\n ToR\n Enter 8",
+        );
+        assert_eq!(m.state(), MachineState::Trapped(Trap::ReturnStackOverflow));
+        assert!(m.memory().is_empty());
+        assert_eq!(m.return_stack(), [1]);
+
+        let m = run_with(
+            MachineConfig {
+                max_memory: Some(8),
+                ..Default::default()
+            },
+            "Alloc 4\n Enter 8",
+        );
+        assert_eq!(
+            m.state(),
+            MachineState::Trapped(Trap::OutOfMemory { size: 8 })
+        );
+        assert_eq!(m.memory().len(), 4);
+        assert!(m.return_stack().is_empty());
+        assert_eq!(m.fp(), 0);
+    }
+
+    #[test]
+    fn run_counts_executed_instructions() {
+        let program = assemble("Im8 1\n Im8 2\n Add\n Interupt\n Im8 0\n Swap\n Div").unwrap();
+        let mut m = Machine::new();
+        let outcome = |reason, executed| RunOutcome { reason, executed };
+        assert_eq!(
+            m.run(&program, Some(2)),
+            outcome(StopReason::BudgetExhausted, 2)
+        );
+        assert_eq!(m.pc(), 4);
+        assert_eq!(m.run(&program, None), outcome(StopReason::Interupted, 2));
+        assert_eq!(m.run(&program, None), outcome(StopReason::Interupted, 0));
+        m.resume();
+        // the trapping instruction counts
+        assert_eq!(
+            m.run(&program, None),
+            outcome(StopReason::Trapped(Trap::DivideByZero), 3)
+        );
+        assert_eq!(m.run(&program, Some(0)).executed, 0);
+    }
+
+    #[test]
+    fn out_of_fuel_is_not_executed() {
+        let program = assemble("Im8 1\n Im8 2\n Add\n Im8 3\n Mul").unwrap();
+        let mut m = Machine::new();
+        m.set_fuel(Some(2));
+        let out_of_fuel = StopReason::Trapped(Trap::OutOfFuel { cost: 1, fuel: 0 });
+        let res = m.run(&program, None);
+        assert_eq!((res.reason, res.executed), (out_of_fuel.clone(), 2));
+        assert_eq!(m.pc(), 4);
+        // the same instruction runs after refueling
+        m.refuel(1);
+        let res = m.run(&program, None);
+        assert_eq!((res.reason, res.executed), (out_of_fuel, 1));
+        assert_eq!(m.calculation_stack(), [3]);
+        m.refuel(10);
+        let res = m.run(&program, None);
+        assert_eq!((res.reason, res.executed), (StopReason::Ended, 2));
+        assert_eq!(m.calculation_stack(), [9]);
+        assert_eq!(m.fuel(), Some(8));
+    }
+
+    #[test]
+    fn call_and_ret() {
+        let m = run("
+            Im8 2
+            Call square
+            Im8 sub
+            CallA
+            Interupt
+        square: Dup
+            Mul
+            Ret
+        sub: Im8 1
+            Sub
+            Ret");
+        assert_eq!(m.state(), MachineState::Interupted);
+        assert_eq!(m.calculation_stack(), [3]);
+        assert!(m.return_stack().is_empty());
+
+        // the return address is the instruction after the call
+        let m = run("Call f\n f: Interupt");
+        assert_eq!(m.return_stack(), [9]);
+        let m = run("Im8 3\n CallA\n Interupt");
+        assert_eq!(m.return_stack(), [3]);
+    }
+
+    #[test]
+    fn recursion_and_call_errors() {
+        // counts down from 5 recursively
+        let m = run("
+            Im8 5
+            Call down
+            J end
+        down: Dup
+            Jz done
+            Im8 1
+            Sub
+            Call down
+        done: Ret
+        end: Nop");
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.calculation_stack(), [0]);
+        assert_eq!(
+            run("Ret").state(),
+            MachineState::Trapped(Trap::ReturnStackUnderflow)
+        );
+        assert_eq!(
+            run("CallA").state(),
+            MachineState::Trapped(Trap::StackUnderflow)
+        );
+        // a target past the program ends it
+        let m = run("Im8 200\n CallA");
+        assert_eq!((m.state(), m.pc()), (MachineState::Ended, 200));
+    }
+
+    fn stack_of(src: &str) -> Vec<u64> {
+        let m = run(src);
+        assert_eq!(m.state(), MachineState::Ended, "{src}");
+        m.calculation_stack().to_vec()
+    }
+
+    #[test]
+    fn bitwise_operations() {
+        assert_eq!(
+            stack_of("Im8 0xc\n Im8 0xa\n And\n Im8 0xc\n Im8 0xa\n Or\n Im8 0xc\n Im8 0xa\n Xor"),
+            [0x8, 0xe, 0x6]
+        );
+        assert_eq!(stack_of("Im8 0\n Not\n Im64 -1\n Not"), [u64::MAX, 0]);
+        assert_eq!(
+            stack_of("Im64 0x0102030405060708\n Bswap"),
+            [0x0807060504030201]
+        );
+    }
+
+    #[test]
+    fn rotates_take_the_value_from_the_top() {
+        // amount below, value on top, like Shl
+        assert_eq!(
+            stack_of("Im8 4\n Im8 1\n Shl\n Im8 4\n Im8 1\n Rotl\n Im8 4\n Im8 1\n Rotr"),
+            [16, 16, 1 << 60]
+        );
+        assert_eq!(
+            stack_of("Im8 1\n Im64 0x8000000000000001\n Rotl\n Im8 1\n Im8 3\n Rotr"),
+            [3, 0x8000000000000001]
+        );
+        // amounts are taken modulo 64
+        assert_eq!(
+            stack_of("Im8 64\n Im8 5\n Rotl\n Im8 65\n Im8 5\n Rotl\n Im8 66\n Im8 5\n Rotr"),
+            [5, 10, 0x4000000000000001]
+        );
+        assert_eq!(
+            stack_of("Im64 0x100000001\n Im8 1\n Rotl\n Im64 -1\n Im8 2\n Rotr"),
+            [2, 4]
+        );
+    }
+
+    #[test]
+    fn bit_counts() {
+        assert_eq!(stack_of("Im8 0\n Clz\n Im8 0\n Ctz"), [64, 64]);
+        assert_eq!(stack_of("Im8 1\n Clz\n Im8 1\n Ctz"), [63, 0]);
+        assert_eq!(stack_of("Im64 -1\n Clz\n Im16 0x100\n Ctz"), [0, 8]);
+        assert_eq!(
+            run("Im8 1\n Rotl").state(),
+            MachineState::Trapped(Trap::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn signed_immediates() {
+        assert_eq!(
+            stack_of("Im8s -1\n Im8s 127\n Im16s -2\n Im16s 0x7fff\n Im32s -3\n Im32s 5"),
+            [u64::MAX, 127, -2i64 as u64, 0x7fff, -3i64 as u64, 5]
+        );
+        // the unsigned forms zero extend
+        assert_eq!(stack_of("Im8 -1\n Im16 -2"), [0xff, 0xfffe]);
+    }
+
+    #[test]
+    fn sign_and_zero_extension() {
+        assert_eq!(
+            stack_of("Im8 0x80\n Sext8\n Im16 0x8000\n Sext16\n Im32 0x80000000\n Sext32"),
+            [0xffffffffffffff80, 0xffffffffffff8000, 0xffffffff80000000]
+        );
+        // only the low bits count, positive values are unchanged
+        assert_eq!(
+            stack_of("Im16 0x17f\n Sext8\n Im32 0x17fff\n Sext16\n Im64 0x17fffffff\n Sext32"),
+            [0x7f, 0x7fff, 0x7fffffff]
+        );
+        assert_eq!(
+            stack_of(
+                "Im64 -1\n Zext8\n Im64 -1\n Zext16\n Im64 -1\n Zext32\n Im8s -2\n Sext8\n Zext8"
+            ),
+            [0xff, 0xffff, 0xffffffff, 0xfe]
+        );
+    }
+
+    #[test]
+    fn sign_extending_loads() {
+        let src = "
+            .data
+                .u32 0xfffffffe, 0x7ffffffe
+            .code
+                Alloc 8
+                Im64 0x7ffffffeffff8081
+                Im8 0
+                Store64
+                Im8 0
+                Load8s
+                Im8 1
+                Load8s
+                Im8 0
+                Load16s
+                Im8 2
+                Load16s
+                Im8 0
+                Load32s
+                Im8 4
+                Load32s
+                Im8 0
+                LoadData8s
+                Im8 3
+                LoadData8s
+                Im8 0
+                LoadData16s
+                Im8 6
+                LoadData16s
+                Im8 0
+                LoadData32s
+                Im8 4
+                LoadData32s";
+        assert_eq!(
+            stack_of(src),
+            [
+                0xffffffffffffff81,
+                0xffffffffffffff80,
+                0xffffffffffff8081,
+                0xffffffffffffffff,
+                0xffffffffffff8081,
+                0x7ffffffe,
+                0xfffffffffffffffe,
+                0xffffffffffffffff,
+                0xfffffffffffffffe,
+                0x7fff,
+                0xfffffffffffffffe,
+                0x7ffffffe,
+            ]
+        );
+        assert_eq!(
+            run("Alloc 2\n Im8 0\n Load32s").state(),
+            MachineState::Trapped(Trap::MemoryOutOfBounds { addr: 0, width: 4 })
+        );
+        assert_eq!(
+            run(".data\n .u8 1\n .code\n Im8 0\n LoadData16s").state(),
+            MachineState::Trapped(Trap::DataOutOfBounds { addr: 0, width: 2 })
+        );
+    }
+
+    #[test]
+    fn offset_loads_and_stores() {
+        // stores take the base on top and the value below it
+        let m = run("
+            Alloc 32
+            Im8 0x11
+            Im8 4
+            Store8Off 1
+            Im16 0x2222
+            Im8 4
+            Store16Off 2
+            Im32 0x33333333
+            Im8 4
+            Store32Off 4
+            Im64 0x4444444444444444
+            Im8 4
+            Store64Off 12
+            Im8 4
+            Load8Off 1
+            Im8 4
+            Load16Off 2
+            Im8 4
+            Load32Off 4
+            Im8 4
+            Load64Off 12
+            Im8 0
+            Load64Off 8");
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(
+            m.calculation_stack(),
+            [0x11, 0x2222, 0x33333333, 0x4444444444444444, 0x33333333]
+        );
+        assert_eq!(m.memory()[4..9], [0, 0x11, 0x22, 0x22, 0x33]);
+        assert_eq!(m.memory()[15..17], [0, 0x44]);
+    }
+
+    #[test]
+    fn indexed_loads_and_stores_scale_the_index() {
+        // stores take the index on top, the base below it and the value below that
+        let m = run("
+            Alloc 40
+            Im8 0xaa
+            Im8 1
+            Im8 3
+            Store8Idx
+            Im16 0xbbbb
+            Im8 8
+            Im8 1
+            Store16Idx
+            Im32 0xcccccccc
+            Im8 8
+            Im8 2
+            Store32Idx
+            Im64 0xdddddddddddddddd
+            Im8 8
+            Im8 3
+            Store64Idx
+            Im8 1
+            Im8 3
+            Load8Idx
+            Im8 8
+            Im8 1
+            Load16Idx
+            Im8 8
+            Im8 2
+            Load32Idx
+            Im8 8
+            Im8 3
+            Load64Idx");
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(
+            m.calculation_stack(),
+            [0xaa, 0xbbbb, 0xcccccccc, 0xdddddddddddddddd]
+        );
+        assert_eq!(m.memory()[4], 0xaa);
+        assert_eq!(m.memory()[10..12], [0xbb, 0xbb]);
+        assert_eq!(m.memory()[16..20], [0xcc; 4]);
+        assert_eq!(m.memory()[32..40], [0xdd; 8]);
+    }
+
+    #[test]
+    fn address_overflow_is_out_of_bounds() {
+        let trap = |src: &str| match run(src).state() {
+            MachineState::Trapped(t) => t,
+            s => panic!("{src}: {s:?}"),
+        };
+        let oob = |addr, width| Trap::MemoryOutOfBounds { addr, width };
+        assert_eq!(trap("Alloc 8\n Im64 -1\n Load64Off 1"), oob(u64::MAX, 8));
+        assert_eq!(
+            trap("Alloc 8\n Im8 0\n Im64 -2\n Store16Off 2"),
+            oob(u64::MAX - 1, 2)
+        );
+        // index times width overflows
+        assert_eq!(
+            trap("Alloc 8\n Im8 8\n Im64 0x4000000000000000\n Load32Idx"),
+            oob(8, 4)
+        );
+        // base plus the scaled index overflows
+        assert_eq!(
+            trap("Alloc 8\n Im8 0\n Im8 8\n Im64 0x7fffffffffffffff\n Store16Idx"),
+            oob(8, 2)
+        );
+        // in range arithmetic past the end of memory
+        assert_eq!(trap("Alloc 8\n Im8 4\n Load32Off 2"), oob(6, 4));
+        assert_eq!(trap("Alloc 8\n Im8 0\n Im8 1\n Load64Idx"), oob(8, 8));
     }
 }
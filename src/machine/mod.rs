@@ -1,75 +1,341 @@
 pub mod calculation_stack;
+pub mod cost_model;
+pub mod decoded_program;
+pub mod heap;
+pub mod host;
 mod machine_actions;
+pub mod program_image;
 pub mod program_memory;
 pub mod return_stack;
 pub mod runtime_memory;
+pub mod snapshot;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod trace;
+pub mod trap;
+pub mod watch;
 
 use crate::instruction::Instructions;
 use crate::machine::calculation_stack::CalculationStack;
+use crate::machine::cost_model::CostModel;
+use crate::machine::host::HostFunctions;
 use crate::machine::return_stack::ReturnStack;
 use crate::machine::runtime_memory::RuntimeMemory;
+use crate::machine::trace::Tracer;
+use crate::machine::trap::Trap;
+use crate::machine::watch::{WatchEvent, Watchpoint};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MachineState {
-    Running,    // normal
-    Interupted, // Int is called
-    Ended,      // end of program reached
+    Running,       // normal
+    Interupted,    // Int is called
+    Ended,         // end of program reached
+    Trapped(Trap), // a fault stopped the program
+}
+
+// why Machine::run returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Ended,
+    Interupted,
+    Trapped(Trap),
+    // the instruction limit was reached while still running
+    BudgetExhausted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub reason: StopReason,
+    // instructions run by this call, including one that trapped,
+    // not one stopped by OutOfFuel before it ran
+    pub executed: u64,
+}
+
+// resource limits of a machine, None is unbounded
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MachineConfig {
+    // values on the calculation stack
+    pub max_calculation_stack: Option<usize>,
+    // values on the return stack
+    pub max_return_stack: Option<usize>,
+    // bytes of runtime memory, stack and heap region together
+    pub max_memory: Option<u64>,
+    // poison freed heap blocks and trap on double free and use after free
+    pub debug_heap: bool,
 }
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct Machine {
     pc: u64, // program counter, ensured to be 64bits
+    // runtime memory address of the current frame, set by Enter and Leave
+    fp: u64,
     state: MachineState,
     calculation_stack: CalculationStack,
     return_stack: ReturnStack,
     runtime_memory: RuntimeMemory,
+    host_functions: HostFunctions,
+    // None runs unmetered
+    fuel: Option<u64>,
+    cost_model: CostModel,
+    config: MachineConfig,
+    tracer: Option<Box<dyn Tracer>>,
 }
 
 #[allow(dead_code)]
 impl Machine {
     fn next(&mut self) {
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
     }
     fn skip_im(&mut self, n: usize) {
         self.pc += n as u64;
     }
-    fn resume(&mut self) {
-        self.state = MachineState::Running;
+    fn jump(&mut self, addr: u64) {
+        self.pc = addr.wrapping_sub(1); // -1 for later increase
     }
     pub fn state(&self) -> MachineState {
         self.state.clone()
     }
-    fn pop(&mut self) -> u64 {
+    // registry for HostCall targets
+    pub fn host_functions_mut(&mut self) -> &mut HostFunctions {
+        &mut self.host_functions
+    }
+    fn pop(&mut self) -> Result<u64, Trap> {
         self.calculation_stack.pop()
     }
-    fn pop_signed(&mut self) -> i64 {
-        i64::from_le_bytes(self.calculation_stack.pop().to_le_bytes())
+    fn pop_signed(&mut self) -> Result<i64, Trap> {
+        Ok(self.calculation_stack.pop()? as i64)
     }
-    fn push(&mut self, v: u64) {
-        self.calculation_stack.push(v);
+    fn push(&mut self, v: u64) -> Result<(), Trap> {
+        self.calculation_stack.push(v)
     }
-    fn push_signed(&mut self, v: i64) {
-        self.calculation_stack
-            .push(u64::from_le_bytes(v.to_le_bytes()));
+    fn push_signed(&mut self, v: i64) -> Result<(), Trap> {
+        self.calculation_stack.push(v as u64)
     }
-    fn r_pop(&mut self) -> u64 {
+    fn r_pop(&mut self) -> Result<u64, Trap> {
         self.return_stack.pop()
     }
-    fn r_push(&mut self, v: u64) {
-        self.return_stack.push(v);
+    fn r_push(&mut self, v: u64) -> Result<(), Trap> {
+        self.return_stack.push(v)
+    }
+    // address of `width` bytes at `base` + `offset`, an overflow is out of bounds
+    fn address(base: u64, offset: u64, width: u64) -> Result<u64, Trap> {
+        base.checked_add(offset)
+            .ok_or(Trap::MemoryOutOfBounds { addr: base, width })
+    }
+    // address of element `index` of `width` bytes wide elements from `base`
+    fn indexed(base: u64, index: u64, width: u64) -> Result<u64, Trap> {
+        index
+            .checked_mul(width)
+            .ok_or(Trap::MemoryOutOfBounds { addr: base, width })
+            .and_then(|offset| Self::address(base, offset, width))
+    }
+    // take the fuel for `ins` before it runs, nothing is taken when it is not enough
+    fn charge(&mut self, ins: &Instructions, im: u64) -> Result<(), Trap> {
+        if let Some(fuel) = self.fuel {
+            let size = match ins {
+                Instructions::AllocS | Instructions::Malloc | Instructions::Realloc => {
+                    self.calculation_stack.as_slice().last().copied()
+                }
+                _ => Some(im),
+            };
+            let cost = self.cost_model.charge(ins, size.unwrap_or(0));
+            self.fuel = Some(
+                fuel.checked_sub(cost)
+                    .ok_or(Trap::OutOfFuel { cost, fuel })?,
+            );
+        }
+        Ok(())
     }
 }
 
 #[allow(dead_code)]
 impl Machine {
     pub fn new() -> Machine {
+        Self::with_config(MachineConfig::default())
+    }
+    pub fn with_config(config: MachineConfig) -> Machine {
+        let mut runtime_memory = RuntimeMemory::with_limit(config.max_memory);
+        runtime_memory.heap.debug = config.debug_heap;
         Machine {
             pc: 0u64,
+            fp: 0u64,
             state: MachineState::Running,
-            calculation_stack: CalculationStack::new(),
-            return_stack: ReturnStack::new(),
-            runtime_memory: RuntimeMemory::new(),
+            calculation_stack: CalculationStack::with_limit(config.max_calculation_stack),
+            return_stack: ReturnStack::with_limit(config.max_return_stack),
+            runtime_memory,
+            host_functions: HostFunctions::new(),
+            fuel: None,
+            cost_model: CostModel::new(),
+            config,
+            tracer: None,
         }
     }
 }
+
+// embedding api
+impl Machine {
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+    pub fn fp(&self) -> u64 {
+        self.fp
+    }
+    // continue after Interupt, other states are left unchanged
+    pub fn resume(&mut self) {
+        if self.state == MachineState::Interupted {
+            self.state = MachineState::Running;
+        }
+    }
+    pub fn push_arg(&mut self, v: u64) -> Result<(), Trap> {
+        self.push(v)
+    }
+    pub fn push_arg_i64(&mut self, v: i64) -> Result<(), Trap> {
+        self.push_signed(v)
+    }
+    pub fn push_arg_f64(&mut self, v: f64) -> Result<(), Trap> {
+        self.push(v.to_bits())
+    }
+    pub fn pop_result(&mut self) -> Result<u64, Trap> {
+        self.pop()
+    }
+    pub fn pop_result_i64(&mut self) -> Result<i64, Trap> {
+        self.pop_signed()
+    }
+    pub fn pop_result_f64(&mut self) -> Result<f64, Trap> {
+        Ok(f64::from_bits(self.pop()?))
+    }
+    // bottom first, the top of stack is the last element
+    pub fn calculation_stack(&self) -> &[u64] {
+        self.calculation_stack.as_slice()
+    }
+    pub fn return_stack(&self) -> &[u64] {
+        self.return_stack.as_slice()
+    }
+    pub fn memory(&self) -> &[u8] {
+        self.runtime_memory.as_slice()
+    }
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.runtime_memory.as_mut_slice()
+    }
+    // heap region, byte i is at address heap::HEAP_BASE + i
+    pub fn heap_memory(&self) -> &[u8] {
+        &self.runtime_memory.heap.raw
+    }
+    // fuel left, None when running unmetered
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+    // add fuel, a machine stopped by OutOfFuel continues at the same instruction
+    pub fn refuel(&mut self, amount: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(amount));
+        if let MachineState::Trapped(Trap::OutOfFuel { .. }) = self.state {
+            self.state = MachineState::Running;
+        }
+    }
+    // watch runtime memory accesses, returns the watchpoint id
+    pub fn watch(&mut self, w: Watchpoint) -> usize {
+        self.runtime_memory.watches.add(w)
+    }
+    pub fn unwatch(&mut self, id: usize) -> Option<Watchpoint> {
+        self.runtime_memory.watches.remove(id)
+    }
+    pub fn watchpoints(&self) -> Vec<(usize, &Watchpoint)> {
+        self.runtime_memory.watches.points().collect()
+    }
+    // accesses recorded since the last take_watch_events, oldest first
+    pub fn watch_events(&self) -> &[WatchEvent] {
+        self.runtime_memory.watches.events()
+    }
+    pub fn take_watch_events(&mut self) -> Vec<WatchEvent> {
+        self.runtime_memory.watches.take_events()
+    }
+    pub fn config(&self) -> &MachineConfig {
+        &self.config
+    }
+    pub fn cost_model(&self) -> &CostModel {
+        &self.cost_model
+    }
+    pub fn set_cost_model(&mut self, cost_model: CostModel) {
+        self.cost_model = cost_model;
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn host_round_trip() {
+        let program = assemble(
+            "
+            Im64 1.5
+            Addf
+            ToR
+            Alloc 8
+            Interupt
+            Im8 0
+            Load64
+            Add
+            FromR",
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.push_arg(40).unwrap();
+        m.push_arg_i64(-3).unwrap();
+        m.push_arg_f64(2.5).unwrap();
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Interupted);
+        assert_eq!(m.return_stack(), [4.0f64.to_bits()]);
+
+        // the host fills memory while the program waits
+        m.memory_mut().copy_from_slice(&2u64.to_le_bytes());
+        m.resume();
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.pop_result_f64(), Ok(4.0));
+        assert_eq!(m.pop_result_i64(), Ok(-1));
+        assert_eq!(m.pop_result(), Ok(40));
+        assert_eq!(m.pop_result(), Err(Trap::StackUnderflow));
+    }
+
+    #[test]
+    fn set_pc_and_resume() {
+        let program = assemble("Im8 1\n Interupt\n Im8 2").unwrap();
+        let mut m = Machine::new();
+        m.run(&program, None);
+        assert_eq!((m.state(), m.pc()), (MachineState::Interupted, 3));
+        // run the first instruction again
+        m.set_pc(0);
+        m.resume();
+        assert_eq!(m.state(), MachineState::Running);
+        m.run(&program, Some(1));
+        assert_eq!(m.calculation_stack(), [1, 1]);
+
+        m.set_pc(3);
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.calculation_stack(), [1, 1, 2]);
+        // only Interupted continues
+        m.resume();
+        assert_eq!(m.state(), MachineState::Ended);
+
+        let mut m = Machine::new();
+        m.run(&assemble("Add").unwrap(), None);
+        m.resume();
+        assert_eq!(m.state(), MachineState::Trapped(Trap::StackUnderflow));
+    }
+}
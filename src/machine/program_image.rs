@@ -0,0 +1,420 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::instruction::ISA_VERSION;
+use crate::machine::program_memory::{ProgramMemory, Section, SourceLine, Symbol};
+
+// `.sirb` program image
+//
+// header, 16 bytes
+//   magic         b"SIRB"
+//   format        u16, FORMAT_VERSION
+//   isa           u16, ISA_VERSION the program was built for
+//   sections      u16, number of section table entries
+//   reserved      u16, 0
+//   table crc     u32, crc32 of the section table
+// section table, 24 bytes per entry
+//   kind          u16, see SectionKind
+//   reserved      u16, 0
+//   crc           u32, crc32 of the payload
+//   offset        u64, from the start of the image
+//   length        u64
+// payloads
+//
+// all integers are little endian
+pub const MAGIC: [u8; 4] = *b"SIRB";
+pub const FORMAT_VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Code = 1,
+    Data = 2,
+    // optional
+    Symbols = 3,
+    // optional, source line table
+    Debug = 4,
+}
+
+impl SectionKind {
+    fn of_u16(v: u16) -> Option<Self> {
+        match v {
+            1 => Some(Self::Code),
+            2 => Some(Self::Data),
+            3 => Some(Self::Symbols),
+            4 => Some(Self::Debug),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedFormat(u16),
+    // the image needs a newer instruction set than this interpreter
+    UnsupportedIsa(u16),
+    Truncated,
+    UnknownSection(u16),
+    DuplicateSection(SectionKind),
+    MissingSection(SectionKind),
+    ChecksumMismatch(Option<SectionKind>),
+    // a section payload could not be decoded
+    Malformed(SectionKind),
+    // a section has more entries or a longer name than its fields can hold
+    TooLarge(SectionKind),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "i/o error: {e}"),
+            Self::BadMagic => write!(f, "not a program image, bad magic number"),
+            Self::UnsupportedFormat(v) => write!(
+                f,
+                "unsupported image format version {v}, expected {FORMAT_VERSION}"
+            ),
+            Self::UnsupportedIsa(v) => write!(
+                f,
+                "image needs isa version {v}, this machine supports up to {ISA_VERSION}"
+            ),
+            Self::Truncated => write!(f, "image is truncated"),
+            Self::UnknownSection(k) => write!(f, "unknown section kind {k}"),
+            Self::DuplicateSection(k) => write!(f, "duplicate {k:?} section"),
+            Self::MissingSection(k) => write!(f, "missing {k:?} section"),
+            Self::ChecksumMismatch(None) => write!(f, "section table checksum mismatch"),
+            Self::ChecksumMismatch(Some(k)) => write!(f, "{k:?} section checksum mismatch"),
+            Self::Malformed(k) => write!(f, "malformed {k:?} section"),
+            Self::TooLarge(k) => write!(f, "{k:?} section too large for the image format"),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<Truncated> for ImageError {
+    fn from(_: Truncated) -> Self {
+        Self::Truncated
+    }
+}
+
+impl ProgramMemory {
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), ImageError> {
+        let mut sections = vec![
+            (SectionKind::Code, self.prog().to_vec()),
+            (SectionKind::Data, self.data().to_vec()),
+        ];
+        if !self.symbols().is_empty() {
+            sections.push((SectionKind::Symbols, encode_symbols(self.symbols())?));
+        }
+        if !self.lines().is_empty() {
+            sections.push((SectionKind::Debug, encode_lines(self.lines())?));
+        }
+        let mut table = Vec::new();
+        let mut offset = (HEADER_SIZE + ENTRY_SIZE * sections.len()) as u64;
+        for (kind, payload) in &sections {
+            table.extend_from_slice(&(*kind as u16).to_le_bytes());
+            table.extend_from_slice(&0u16.to_le_bytes());
+            table.extend_from_slice(&crc32(payload).to_le_bytes());
+            table.extend_from_slice(&offset.to_le_bytes());
+            table.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            offset += payload.len() as u64;
+        }
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&ISA_VERSION.to_le_bytes());
+        header.extend_from_slice(&(sections.len() as u16).to_le_bytes()); // at most 4 kinds
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&crc32(&table).to_le_bytes());
+        writer.write_all(&header)?;
+        writer.write_all(&table)?;
+        for (_, payload) in &sections {
+            writer.write_all(payload)?;
+        }
+        Ok(())
+    }
+
+    pub fn load_from<R: Read>(mut reader: R) -> Result<Self, ImageError> {
+        let mut image = Vec::new();
+        reader.read_to_end(&mut image)?;
+        let mut header = Cursor(&image);
+        if header.take(4)? != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let format = header.u16()?;
+        if format != FORMAT_VERSION {
+            return Err(ImageError::UnsupportedFormat(format));
+        }
+        let isa = header.u16()?;
+        if isa > ISA_VERSION {
+            return Err(ImageError::UnsupportedIsa(isa));
+        }
+        let count = header.u16()? as usize;
+        header.u16()?;
+        let table_crc = header.u32()?;
+        let table = header.take(count * ENTRY_SIZE)?;
+        if crc32(table) != table_crc {
+            return Err(ImageError::ChecksumMismatch(None));
+        }
+
+        let mut found: [Option<&[u8]>; 4] = [None; 4];
+        let mut table = Cursor(table);
+        for _ in 0..count {
+            let raw_kind = table.u16()?;
+            let kind = SectionKind::of_u16(raw_kind).ok_or(ImageError::UnknownSection(raw_kind))?;
+            table.u16()?;
+            let crc = table.u32()?;
+            let offset = table.u64()?;
+            let len = table.u64()?;
+            let payload = usize::try_from(offset)
+                .ok()
+                .zip(usize::try_from(len).ok())
+                .and_then(|(o, l)| image.get(o..o.checked_add(l)?))
+                .ok_or(ImageError::Truncated)?;
+            if crc32(payload) != crc {
+                return Err(ImageError::ChecksumMismatch(Some(kind)));
+            }
+            let slot = &mut found[kind as usize - 1];
+            if slot.is_some() {
+                return Err(ImageError::DuplicateSection(kind));
+            }
+            *slot = Some(payload);
+        }
+        let [code, data, symbols, lines] = found;
+        let code = code.ok_or(ImageError::MissingSection(SectionKind::Code))?;
+        let data = data.ok_or(ImageError::MissingSection(SectionKind::Data))?;
+        let symbols = match symbols {
+            Some(s) => decode_symbols(s).ok_or(ImageError::Malformed(SectionKind::Symbols))?,
+            None => Vec::new(),
+        };
+        let lines = match lines {
+            Some(l) => decode_lines(l).ok_or(ImageError::Malformed(SectionKind::Debug))?,
+            None => Vec::new(),
+        };
+        Ok(ProgramMemory::new(code.to_vec(), data.to_vec())
+            .with_symbols(symbols)
+            .with_lines(lines))
+    }
+}
+
+// symbols: count u32, then per symbol section u8, offset u64, name length u16, utf-8 name
+fn encode_symbols(symbols: &[Symbol]) -> Result<Vec<u8>, ImageError> {
+    let too_large = || ImageError::TooLarge(SectionKind::Symbols);
+    let mut res = Vec::new();
+    let count = u32::try_from(symbols.len()).map_err(|_| too_large())?;
+    res.extend_from_slice(&count.to_le_bytes());
+    for s in symbols {
+        res.push(match s.section {
+            Section::Code => 0,
+            Section::Data => 1,
+        });
+        res.extend_from_slice(&s.offset.to_le_bytes());
+        let len = u16::try_from(s.name.len()).map_err(|_| too_large())?;
+        res.extend_from_slice(&len.to_le_bytes());
+        res.extend_from_slice(s.name.as_bytes());
+    }
+    Ok(res)
+}
+
+fn decode_symbols(bytes: &[u8]) -> Option<Vec<Symbol>> {
+    let mut c = Cursor(bytes);
+    let count = c.u32().ok()?;
+    let mut res = Vec::new();
+    for _ in 0..count {
+        let section = match c.u8().ok()? {
+            0 => Section::Code,
+            1 => Section::Data,
+            _ => return None,
+        };
+        let offset = c.u64().ok()?;
+        let len = c.u16().ok()? as usize;
+        let name = String::from_utf8(c.take(len).ok()?.to_vec()).ok()?;
+        res.push(Symbol {
+            name,
+            section,
+            offset,
+        });
+    }
+    c.is_empty().then_some(res)
+}
+
+// line table: count u32, then per entry offset u64, line u32
+fn encode_lines(lines: &[SourceLine]) -> Result<Vec<u8>, ImageError> {
+    let too_large = || ImageError::TooLarge(SectionKind::Debug);
+    let mut res = Vec::new();
+    let count = u32::try_from(lines.len()).map_err(|_| too_large())?;
+    res.extend_from_slice(&count.to_le_bytes());
+    for l in lines {
+        res.extend_from_slice(&l.offset.to_le_bytes());
+        res.extend_from_slice(&l.line.to_le_bytes());
+    }
+    Ok(res)
+}
+
+fn decode_lines(bytes: &[u8]) -> Option<Vec<SourceLine>> {
+    let mut c = Cursor(bytes);
+    let count = c.u32().ok()?;
+    let mut res = Vec::new();
+    for _ in 0..count {
+        let offset = c.u64().ok()?;
+        let line = c.u32().ok()?;
+        res.push(SourceLine { offset, line });
+    }
+    c.is_empty().then_some(res)
+}
+
+// a read past the end of a Cursor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Truncated;
+
+// little endian reader over a byte slice, shared by images and snapshots
+pub(crate) struct Cursor<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], Truncated> {
+        if n > self.0.len() {
+            return Err(Truncated);
+        }
+        let (a, b) = self.0.split_at(n);
+        self.0 = b;
+        Ok(a)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Truncated> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+    pub(crate) fn u8(&mut self) -> Result<u8, Truncated> {
+        Ok(self.take(1)?[0])
+    }
+    pub(crate) fn u16(&mut self) -> Result<u16, Truncated> {
+        self.array().map(u16::from_le_bytes)
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32, Truncated> {
+        self.array().map(u32::from_le_bytes)
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, Truncated> {
+        self.array().map(u64::from_le_bytes)
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// crc-32 (ieee 802.3)
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const SOURCE: &str = "
+        .data
+        table: .u64 1, 2, 3
+        .code
+        start: Im8 table
+               LoadData64
+               Jz start
+               HostCall 7";
+
+    fn encode(program: &ProgramMemory) -> Vec<u8> {
+        let mut image = Vec::new();
+        program.write_to(&mut image).unwrap();
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = assemble(SOURCE).unwrap();
+        assert!(!program.symbols().is_empty());
+        assert!(!program.lines().is_empty());
+        let loaded = ProgramMemory::load_from(&encode(&program)[..]).unwrap();
+        assert_eq!(loaded.prog(), program.prog());
+        assert_eq!(loaded.data(), program.data());
+        assert_eq!(loaded.symbols(), program.symbols());
+        assert_eq!(loaded.lines(), program.lines());
+
+        // optional sections are left out when empty
+        let bare = ProgramMemory::new(vec![0, 0], vec![]);
+        let image = encode(&bare);
+        assert_eq!(image.len(), HEADER_SIZE + 2 * ENTRY_SIZE + 2);
+        let loaded = ProgramMemory::load_from(&image[..]).unwrap();
+        assert_eq!(loaded.prog(), [0, 0]);
+        assert!(loaded.symbols().is_empty());
+    }
+
+    #[test]
+    fn long_symbol_names_are_rejected() {
+        let symbol = |len| Symbol {
+            name: "x".repeat(len),
+            section: Section::Code,
+            offset: 0,
+        };
+        let program =
+            ProgramMemory::new(vec![0], vec![]).with_symbols(vec![symbol(u16::MAX as usize)]);
+        let loaded = ProgramMemory::load_from(&encode(&program)[..]).unwrap();
+        assert_eq!(loaded.symbols(), program.symbols());
+
+        let program = ProgramMemory::new(vec![0], vec![]).with_symbols(vec![symbol(1 << 16)]);
+        assert!(matches!(
+            program.write_to(Vec::new()),
+            Err(ImageError::TooLarge(SectionKind::Symbols))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let image = encode(&assemble(SOURCE).unwrap());
+        let load = |b: &[u8]| ProgramMemory::load_from(b).unwrap_err();
+
+        let mut bad = image.clone();
+        bad[0] = b'X';
+        assert!(matches!(load(&bad), ImageError::BadMagic));
+        let mut bad = image.clone();
+        bad[4] = 9;
+        assert!(matches!(load(&bad), ImageError::UnsupportedFormat(9)));
+        let mut bad = image.clone();
+        bad[6..8].copy_from_slice(&(ISA_VERSION + 1).to_le_bytes());
+        assert!(matches!(load(&bad), ImageError::UnsupportedIsa(_)));
+        assert!(matches!(load(&image[..20]), ImageError::Truncated));
+        assert!(matches!(
+            load(&image[..image.len() - 1]),
+            ImageError::Truncated
+        ));
+        // the first entry's kind is covered by the table checksum
+        let mut bad = image.clone();
+        bad[HEADER_SIZE] = 2;
+        assert!(matches!(load(&bad), ImageError::ChecksumMismatch(None)));
+        // the last byte of the last payload
+        let mut bad = image.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert!(matches!(load(&bad), ImageError::ChecksumMismatch(Some(_))));
+    }
+}
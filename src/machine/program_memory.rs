@@ -1,7 +1,29 @@
+use crate::machine::trap::Trap;
 use crate::machine::Instructions;
-use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    Code,
+    Data,
+}
+
+// named offset into a section, e.g. an assembler label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: u64,
+}
+
+// source line an instruction was assembled from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine {
+    pub offset: u64,
+    pub line: u32,
+}
 
 #[allow(dead_code)]
+#[derive(Debug)]
 pub struct ProgramMemory {
     // program memory
     prog: Vec<u8>,
@@ -9,78 +31,107 @@ pub struct ProgramMemory {
     // it should be encoded into bytes
     // unchangable
     data: Vec<u8>,
+    // optional debug information
+    symbols: Vec<Symbol>,
+    lines: Vec<SourceLine>,
 }
 
 #[allow(dead_code)]
 impl ProgramMemory {
     pub fn new(prog: Vec<u8>, data: Vec<u8>) -> Self {
-        Self { prog, data }
+        Self {
+            prog,
+            data,
+            symbols: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+    pub fn with_symbols(mut self, symbols: Vec<Symbol>) -> Self {
+        self.symbols = symbols;
+        self
+    }
+    pub fn with_lines(mut self, lines: Vec<SourceLine>) -> Self {
+        self.lines = lines;
+        self
     }
     pub fn prog_len(&self) -> usize {
         self.prog.len()
     }
-    pub(crate) fn get_opcode_at(&self, index: u64) -> Instructions {
-        let opcode = self.prog[index as usize];
-        Instructions::of_opcode(opcode)
-            .expect(format!("get_opcode error: unsupported opcode {opcode}").as_str())
-    }
-    pub(crate) fn get_im_u8_at(&self, index: u64) -> u8 {
-        self.prog[index as usize]
-    }
-    pub(crate) fn get_im_u16_at(&self, index: u64) -> u16 {
-        let mut buf: [u8; 2] = [0; 2];
-        let mut bytes = &self.prog[(index as usize)..(index as usize + size_of::<u16>())];
-        bytes.read_exact(&mut buf[..size_of::<u16>()]).expect(
-            format!("corrupted program memory at {index} read when getting immediate").as_str(),
-        );
-        u16::from_le_bytes(buf)
-    }
-    pub(crate) fn get_im_u32_at(&self, index: u64) -> u32 {
-        let mut buf: [u8; 4] = [0; 4];
-        let mut bytes = &self.prog[(index as usize)..(index as usize + size_of::<u32>())];
-        bytes.read_exact(&mut buf[..size_of::<u32>()]).expect(
-            format!("corrupted program memory at {index} read when getting immediate").as_str(),
-        );
-        u32::from_le_bytes(buf)
-    }
-    pub(crate) fn get_im_u64_at(&self, index: u64) -> u64 {
-        let mut buf: [u8; 8] = [0; 8];
-        let mut bytes = &self.prog[(index as usize)..(index as usize + size_of::<u64>())];
-        bytes.read_exact(&mut buf[..size_of::<u64>()]).expect(
-            format!("corrupted program memory at {index} read when getting immediate").as_str(),
-        );
-        u64::from_le_bytes(buf)
-    }
-    pub(crate) fn get_data_u8(&self, index: u64) -> u8 {
-        self.data
-            .get(index as usize)
-            .expect(
-                format!("corrupted program memory at {index} read when getting immediate").as_str(),
-            )
-            .clone()
-    }
-    pub(crate) fn get_data_u16(&self, index: u64) -> u16 {
-        let mut buf: [u8; 2] = [0; 2];
-        let mut bytes = &self.data[(index as usize)..(index as usize + size_of::<u16>())];
-        bytes.read_exact(&mut buf[..size_of::<u16>()]).expect(
-            format!("corrupted program memory at {index} read when getting immediate").as_str(),
-        );
-        u16::from_le_bytes(buf)
-    }
-    pub(crate) fn get_data_u32(&self, index: u64) -> u32 {
-        let mut buf: [u8; 4] = [0; 4];
-        let mut bytes = &self.data[(index as usize)..(index as usize + size_of::<u32>())];
-        bytes.read_exact(&mut buf[..size_of::<u32>()]).expect(
-            format!("corrupted program memory at {index} read when getting immediate").as_str(),
-        );
-        u32::from_le_bytes(buf)
-    }
-    pub(crate) fn get_data_u64_at(&self, index: u64) -> u64 {
-        let mut buf: [u8; 8] = [0; 8];
-        let mut bytes = &self.data[(index as usize)..(index as usize + size_of::<u64>())];
-        bytes.read_exact(&mut buf[..size_of::<u64>()]).expect(
-            format!("corrupted program memory at {index} read when getting immediate").as_str(),
-        );
-        u64::from_le_bytes(buf)
+    pub fn prog(&self) -> &[u8] {
+        &self.prog
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+    pub fn lines(&self) -> &[SourceLine] {
+        &self.lines
+    }
+    pub(crate) fn get_opcode_at(&self, index: u64) -> Result<Instructions, Trap> {
+        let opcode = self.get_im_u8_at(index)?;
+        Instructions::of_opcode(opcode).ok_or(Trap::InvalidOpcode {
+            pc: index,
+            byte: opcode,
+        })
+    }
+    fn get_im<const N: usize>(&self, index: u64) -> Result<[u8; N], Trap> {
+        let mut buf = [0u8; N];
+        let bytes = usize::try_from(index)
+            .ok()
+            .and_then(|i| self.prog.get(i..i.checked_add(N)?))
+            .ok_or(Trap::ProgramOutOfBounds { pc: index })?;
+        buf.copy_from_slice(bytes);
+        Ok(buf)
+    }
+    pub(crate) fn get_im_u8_at(&self, index: u64) -> Result<u8, Trap> {
+        Ok(u8::from_le_bytes(self.get_im(index)?))
+    }
+    pub(crate) fn get_im_u16_at(&self, index: u64) -> Result<u16, Trap> {
+        Ok(u16::from_le_bytes(self.get_im(index)?))
+    }
+    pub(crate) fn get_im_u32_at(&self, index: u64) -> Result<u32, Trap> {
+        Ok(u32::from_le_bytes(self.get_im(index)?))
+    }
+    pub(crate) fn get_im_u64_at(&self, index: u64) -> Result<u64, Trap> {
+        Ok(u64::from_le_bytes(self.get_im(index)?))
+    }
+    // immediate of `width` bytes, zero extended
+    pub(crate) fn get_im_at(&self, index: u64, width: usize) -> Result<u64, Trap> {
+        Ok(match width {
+            0 => 0,
+            1 => self.get_im_u8_at(index)? as u64,
+            2 => self.get_im_u16_at(index)? as u64,
+            4 => self.get_im_u32_at(index)? as u64,
+            _ => self.get_im_u64_at(index)?,
+        })
+    }
+    fn get_data<const N: usize>(&self, index: u64) -> Result<[u8; N], Trap> {
+        let mut buf = [0u8; N];
+        let bytes = usize::try_from(index)
+            .ok()
+            .and_then(|i| self.data.get(i..i.checked_add(N)?))
+            .ok_or(Trap::DataOutOfBounds {
+                addr: index,
+                width: N as u64,
+            })?;
+        buf.copy_from_slice(bytes);
+        Ok(buf)
+    }
+    pub(crate) fn get_data_u8(&self, index: u64) -> Result<u8, Trap> {
+        Ok(u8::from_le_bytes(self.get_data(index)?))
+    }
+    pub(crate) fn get_data_u16(&self, index: u64) -> Result<u16, Trap> {
+        Ok(u16::from_le_bytes(self.get_data(index)?))
+    }
+    pub(crate) fn get_data_u32(&self, index: u64) -> Result<u32, Trap> {
+        Ok(u32::from_le_bytes(self.get_data(index)?))
+    }
+    pub(crate) fn get_data_u64(&self, index: u64) -> Result<u64, Trap> {
+        Ok(u64::from_le_bytes(self.get_data(index)?))
     }
 }
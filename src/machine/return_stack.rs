@@ -1,18 +1,84 @@
+use crate::machine::trap::Trap;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub(crate) struct ReturnStack {
     raw: Vec<u64>,
+    // maximum number of return addresses and ToR values
+    max: usize,
 }
 
 #[allow(dead_code)]
 impl ReturnStack {
     pub(crate) fn new() -> ReturnStack {
-        ReturnStack { raw: Vec::new() }
+        Self::with_limit(None)
+    }
+    pub(crate) fn with_limit(max: Option<usize>) -> ReturnStack {
+        ReturnStack {
+            raw: Vec::new(),
+            max: max.unwrap_or(usize::MAX),
+        }
+    }
+    // swap in restored contents, the caller checked them against the limit
+    pub(crate) fn replace(&mut self, raw: Vec<u64>) {
+        self.raw = raw;
+    }
+    pub(crate) fn as_slice(&self) -> &[u64] {
+        &self.raw
+    }
+    pub(crate) fn push(&mut self, v: u64) -> Result<(), Trap> {
+        if self.raw.len() >= self.max {
+            return Err(Trap::ReturnStackOverflow);
+        }
+        self.raw.push(v);
+        Ok(())
+    }
+    pub(crate) fn pop(&mut self) -> Result<u64, Trap> {
+        self.raw.pop().ok_or(Trap::ReturnStackUnderflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::test_util::run_with;
+    use crate::machine::{Machine, MachineConfig, MachineState};
+
+    fn run_limited(src: &str, max: usize) -> Machine {
+        let config = MachineConfig {
+            max_return_stack: Some(max),
+            ..MachineConfig::default()
+        };
+        run_with(config, src)
     }
-    pub(crate) fn push(&mut self, v: u64) {
-        self.raw.push(v)
+
+    #[test]
+    fn push_stops_at_the_limit() {
+        let mut stack = ReturnStack::with_limit(Some(1));
+        stack.push(1).unwrap();
+        assert_eq!(stack.push(2), Err(Trap::ReturnStackOverflow));
+        assert_eq!(stack.pop(), Ok(1));
+        assert_eq!(stack.pop(), Err(Trap::ReturnStackUnderflow));
     }
-    pub(crate) fn pop(&mut self) -> u64 {
-        self.raw.pop().expect("return stack underflowed.")
+
+    #[test]
+    fn calls_and_tor_share_the_limit() {
+        let machine = run_limited("f: Call f", 3);
+        assert_eq!(
+            machine.state(),
+            MachineState::Trapped(Trap::ReturnStackOverflow)
+        );
+        assert_eq!(machine.return_stack(), [9; 3]);
+
+        let machine = run_limited("Im8 1\n ToR\n Im8 2\n ToR\n Call f\n f: Ret", 2);
+        assert_eq!(
+            machine.state(),
+            MachineState::Trapped(Trap::ReturnStackOverflow)
+        );
+        assert_eq!(machine.return_stack(), [1, 2]);
+
+        let machine = run_limited("Im8 1\n ToR\n Call f\n FromR\n J 100\n f: Ret", 2);
+        assert_eq!(machine.state(), MachineState::Ended);
+        assert_eq!(machine.calculation_stack(), [1]);
     }
 }
@@ -1,120 +1,297 @@
-use std::io::Read;
+use std::ops::Range;
+
+use crate::machine::heap::{Heap, HEAP_BASE};
+use crate::machine::trace::MemoryWrite;
+use crate::machine::trap::Trap;
+use crate::machine::watch::{le_u64, Access, Watches};
+
+// extend `raw` with zeros to `len` bytes, false when the host cannot allocate them
+pub(crate) fn grow_zeroed(raw: &mut Vec<u8>, len: u64) -> bool {
+    let Some(extra) = usize::try_from(len)
+        .ok()
+        .and_then(|l| l.checked_sub(raw.len()))
+    else {
+        return false;
+    };
+    if raw.try_reserve_exact(extra).is_err() {
+        return false;
+    }
+    raw.resize(raw.len() + extra, 0);
+    true
+}
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub(crate) struct RuntimeMemory {
     // addr, value
     raw: Vec<u8>,
+    // maximum size in bytes of both regions
+    max: u64,
+    // addresses from HEAP_BASE on
+    pub(crate) heap: Heap,
+    pub(crate) watches: Watches,
+    // collects stores while the machine traces an instruction
+    pub(crate) trace_writes: Option<Vec<MemoryWrite>>,
 }
 
 #[allow(dead_code)]
 impl RuntimeMemory {
     pub(crate) fn new() -> Self {
-        Self { raw: Vec::new() }
+        Self::with_limit(None)
+    }
+    pub(crate) fn with_limit(max: Option<u64>) -> Self {
+        Self {
+            raw: Vec::new(),
+            max: max.unwrap_or(u64::MAX),
+            heap: Heap::default(),
+            watches: Watches::default(),
+            trace_writes: None,
+        }
+    }
+    // swap in restored contents, the caller checked them against the limit
+    pub(crate) fn replace(&mut self, raw: Vec<u8>) {
+        self.raw = raw;
+    }
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.raw
+    }
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.raw
+    }
+    // bytes both regions may still grow
+    fn room(&self) -> u64 {
+        self.max
+            .saturating_sub(self.raw.len() as u64)
+            .saturating_sub(self.heap.raw.len() as u64)
     }
-    pub(crate) fn alloc(&mut self, size: u64) {
-        for _ in 0..size {
-            self.raw.push(0)
+    // grow by `size` zeroed bytes
+    pub(crate) fn alloc(&mut self, size: u64) -> Result<(), Trap> {
+        let new_len = (self.raw.len() as u64)
+            .checked_add(size)
+            .filter(|l| size <= self.room() && *l <= HEAP_BASE)
+            .ok_or(Trap::OutOfMemory { size })?;
+        match grow_zeroed(&mut self.raw, new_len) {
+            true => Ok(()),
+            false => Err(Trap::OutOfMemory { size }),
         }
     }
-    pub(crate) fn dealloc(&mut self, size: u64) {
-        for _ in 0..size {
-            self.raw
-                .pop()
-                .expect("dealloc: runtime memory underflowed.");
+    pub(crate) fn dealloc(&mut self, size: u64) -> Result<(), Trap> {
+        if size > self.raw.len() as u64 {
+            return Err(Trap::MemoryUnderflow { size });
         }
+        self.raw.truncate(self.raw.len() - size as usize);
+        Ok(())
+    }
+    pub(crate) fn malloc(&mut self, size: u64) -> Result<u64, Trap> {
+        let room = self.room();
+        self.heap.malloc(size, room)
+    }
+    pub(crate) fn realloc(&mut self, addr: u64, size: u64) -> Result<u64, Trap> {
+        let room = self.room();
+        self.heap.realloc(addr, size, room)
+    }
+    // bounds checked byte range of an access, `true` for the heap region
+    fn range(&self, start_pos: u64, width: usize) -> Result<(bool, Range<usize>), Trap> {
+        if start_pos >= HEAP_BASE {
+            return Ok((true, self.heap.range(start_pos, width)?));
+        }
+        let oob = Trap::MemoryOutOfBounds {
+            addr: start_pos,
+            width: width as u64,
+        };
+        let start = usize::try_from(start_pos).map_err(|_| oob.clone())?;
+        match start.checked_add(width) {
+            Some(end) if end <= self.raw.len() => Ok((false, start..end)),
+            _ => Err(oob),
+        }
+    }
+    fn get<const N: usize>(&mut self, start_pos: u64) -> Result<[u8; N], Trap> {
+        let (heap, r) = self.range(start_pos, N)?;
+        let mut t = [0u8; N];
+        t.copy_from_slice(&self.region(heap)[r]);
+        if !self.watches.is_empty() {
+            self.watches.hit(Access::Read, start_pos, &t, &t);
+        }
+        Ok(t)
+    }
+    fn region(&self, heap: bool) -> &[u8] {
+        match heap {
+            true => &self.heap.raw,
+            false => &self.raw,
+        }
+    }
+    fn save<const N: usize>(&mut self, value: [u8; N], start_pos: u64) -> Result<(), Trap> {
+        self.local_save_bytes(&value, start_pos)
     }
-    pub(crate) fn local_get_u8(&self, start_pos: u64) -> u8 {
-        self.raw
-            .get(start_pos as usize)
-            .expect(
-                format!(
-                    "local_get_u64: accessing invaild memory location {}.",
-                    start_pos
-                )
-                .as_str(),
-            )
-            .clone()
-    }
-    pub(crate) fn local_save_u8(&mut self, value: u8, start_pos: u64) {
-        self.raw[start_pos as usize] = value;
-    }
-    pub(crate) fn local_get_u16(&self, start_pos: u64) -> u16 {
-        let mut t: [u8; 2] = [0u8; 2];
-        self.raw
-            .get((start_pos as usize)..size_of::<u16>())
-            .expect(
-                format!(
-                    "local_get_u16: accessing invaild memory location {}.",
-                    start_pos
-                )
-                .as_str(),
-            )
-            .read_exact(&mut t)
-            .expect("local_get_u16: invaild memory read");
-        u16::from_le_bytes(t)
-    }
-    pub(crate) fn local_save_u16(&mut self, value: u16, start_pos: u64) {
-        for (a, b) in self
-            .raw
-            .iter_mut()
-            .skip(start_pos as usize)
-            .take(size_of::<u16>())
-            .zip(value.to_le_bytes())
-        {
-            *a = b;
+    pub(crate) fn local_get_bytes(&mut self, start_pos: u64, len: u64) -> Result<&[u8], Trap> {
+        let (heap, r) = self.range(start_pos, len as usize)?;
+        let mem = match heap {
+            true => &self.heap.raw,
+            false => &self.raw,
+        };
+        if !self.watches.is_empty() {
+            let t = &mem[r.clone()];
+            self.watches.hit(Access::Read, start_pos, t, t);
         }
+        Ok(&mem[r])
     }
-    pub(crate) fn local_get_u32(&self, start_pos: u64) -> u32 {
-        let mut t: [u8; 4] = [0u8; 4];
-        self.raw
-            .get((start_pos as usize)..size_of::<u32>())
-            .expect(
-                format!(
-                    "local_get_u32: accessing invaild memory location {}.",
-                    start_pos
-                )
-                .as_str(),
-            )
-            .read_exact(&mut t)
-            .expect("local_get_u32: invaild memory read");
-        u32::from_le_bytes(t)
-    }
-    pub(crate) fn local_save_u32(&mut self, value: u32, start_pos: u64) {
-        for (a, b) in self
-            .raw
-            .iter_mut()
-            .skip(start_pos as usize)
-            .take(size_of::<u32>())
-            .zip(value.to_le_bytes())
-        {
-            *a = b;
+    pub(crate) fn local_save_bytes(&mut self, value: &[u8], start_pos: u64) -> Result<(), Trap> {
+        let (heap, r) = self.range(start_pos, value.len())?;
+        let mem = match heap {
+            true => &mut self.heap.raw,
+            false => &mut self.raw,
+        };
+        if !self.watches.is_empty() {
+            self.watches
+                .hit(Access::Write, start_pos, &mem[r.clone()], value);
         }
+        if let Some(writes) = &mut self.trace_writes {
+            writes.push(MemoryWrite {
+                addr: start_pos,
+                width: value.len() as u64,
+                old: le_u64(&mem[r.clone()]),
+                new: le_u64(value),
+            });
+        }
+        mem[r].copy_from_slice(value);
+        Ok(())
+    }
+    pub(crate) fn local_get_u8(&mut self, start_pos: u64) -> Result<u8, Trap> {
+        Ok(u8::from_le_bytes(self.get(start_pos)?))
+    }
+    pub(crate) fn local_save_u8(&mut self, value: u8, start_pos: u64) -> Result<(), Trap> {
+        self.save(value.to_le_bytes(), start_pos)
+    }
+    pub(crate) fn local_get_u16(&mut self, start_pos: u64) -> Result<u16, Trap> {
+        Ok(u16::from_le_bytes(self.get(start_pos)?))
+    }
+    pub(crate) fn local_save_u16(&mut self, value: u16, start_pos: u64) -> Result<(), Trap> {
+        self.save(value.to_le_bytes(), start_pos)
+    }
+    pub(crate) fn local_get_u32(&mut self, start_pos: u64) -> Result<u32, Trap> {
+        Ok(u32::from_le_bytes(self.get(start_pos)?))
+    }
+    pub(crate) fn local_save_u32(&mut self, value: u32, start_pos: u64) -> Result<(), Trap> {
+        self.save(value.to_le_bytes(), start_pos)
+    }
+    pub(crate) fn local_get_u64(&mut self, start_pos: u64) -> Result<u64, Trap> {
+        Ok(u64::from_le_bytes(self.get(start_pos)?))
+    }
+    pub(crate) fn local_save_u64(&mut self, value: u64, start_pos: u64) -> Result<(), Trap> {
+        self.save(value.to_le_bytes(), start_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::test_util::{run, run_with};
+    use crate::machine::{MachineConfig, MachineState};
+
+    #[test]
+    fn alloc_beyond_the_host_traps() {
+        // unbounded by MachineConfig but more than the host can allocate
+        let size = HEAP_BASE - 8;
+        assert_eq!(
+            run(&format!("Alloc 8\n Alloc64 {size}")).state(),
+            MachineState::Trapped(Trap::OutOfMemory { size })
+        );
+        assert_eq!(
+            run(&format!("Im64 {size}\n AllocS\n Alloc 16")).state(),
+            MachineState::Trapped(Trap::OutOfMemory { size })
+        );
+        // past the stack region
+        assert_eq!(
+            run("Alloc 8\n Im64 -1\n AllocS").state(),
+            MachineState::Trapped(Trap::OutOfMemory { size: u64::MAX })
+        );
+    }
+
+    #[test]
+    fn alloc_and_dealloc() {
+        let mut memory = RuntimeMemory::with_limit(Some(16));
+        memory.alloc(8).unwrap();
+        memory.local_save_u64(7, 0).unwrap();
+        assert_eq!(memory.alloc(9), Err(Trap::OutOfMemory { size: 9 }));
+        memory.alloc(8).unwrap();
+        assert_eq!(memory.local_get_u64(0), Ok(7));
+        assert_eq!(memory.local_get_u64(8), Ok(0));
+        assert_eq!(
+            memory.local_get_u64(9),
+            Err(Trap::MemoryOutOfBounds { addr: 9, width: 8 })
+        );
+        assert_eq!(memory.dealloc(17), Err(Trap::MemoryUnderflow { size: 17 }));
+        memory.dealloc(16).unwrap();
+        assert!(memory.as_slice().is_empty());
     }
-    pub(crate) fn local_get_u64(&self, start_pos: u64) -> u64 {
-        let mut t: [u8; 8] = [0u8; 8];
-        self.raw
-            .get((start_pos as usize)..size_of::<u64>())
-            .expect(
-                format!(
-                    "local_get_u64: accessing invaild memory location {}.",
-                    start_pos
-                )
-                .as_str(),
-            )
-            .read_exact(&mut t)
-            .expect("local_get_u64: invaild memory read");
-        u64::from_le_bytes(t)
-    }
-    pub(crate) fn local_save_u64(&mut self, value: u64, start_pos: u64) {
-        for (a, b) in self
-            .raw
-            .iter_mut()
-            .skip(start_pos as usize)
-            .take(size_of::<u64>())
-            .zip(value.to_le_bytes())
-        {
-            *a = b;
+
+    #[test]
+    fn stack_and_heap_share_max_memory() {
+        let machine = run_with(
+            MachineConfig {
+                max_memory: Some(128),
+                ..MachineConfig::default()
+            },
+            "Alloc 64\n Im8 64\n Malloc\n Im8 1\n Malloc",
+        );
+        assert_eq!(
+            machine.state(),
+            MachineState::Trapped(Trap::OutOfMemory { size: 1 })
+        );
+        assert_eq!(machine.memory().len() + machine.heap_memory().len(), 128);
+        assert_eq!(machine.calculation_stack(), [HEAP_BASE]);
+    }
+
+    #[test]
+    fn alloc_and_dealloc_widths() {
+        let m = run("
+            Alloc 1
+            MemSize
+            Alloc16 0x100
+            MemSize
+            Alloc32 0x10000
+            MemSize
+            Alloc64 2
+            MemSize
+            Im8 3
+            AllocS
+            MemSize
+            Dealloc 1
+            Dealloc16 0x100
+            MemSize
+            Dealloc32 0x10000
+            Dealloc64 2
+            MemSize
+            Im8 3
+            DeallocS
+            MemSize");
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(
+            m.calculation_stack(),
+            [1, 0x101, 0x10101, 0x10103, 0x10106, 0x10005, 3, 0]
+        );
+    }
+
+    #[test]
+    fn dealloc_past_the_start_underflows() {
+        for (src, size) in [
+            ("Alloc 4\n Dealloc 5", 5),
+            ("Alloc 4\n Dealloc16 0x100", 0x100),
+            ("Alloc 4\n Dealloc32 0x10000", 0x10000),
+            ("Alloc 4\n Dealloc64 -1", u64::MAX),
+            ("Alloc 4\n Im8 5\n DeallocS", 5),
+        ] {
+            let m = run(src);
+            assert_eq!(
+                m.state(),
+                MachineState::Trapped(Trap::MemoryUnderflow { size }),
+                "{src}"
+            );
+            assert_eq!(m.memory().len(), 4, "{src}");
         }
+        assert_eq!(
+            run("DeallocS").state(),
+            MachineState::Trapped(Trap::StackUnderflow)
+        );
     }
 }
@@ -0,0 +1,628 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::instruction::ISA_VERSION;
+use crate::machine::heap::Heap;
+use crate::machine::program_image::{crc32, Cursor, Truncated};
+use crate::machine::program_memory::ProgramMemory;
+use crate::machine::{Machine, MachineState, Trap};
+
+// `.sirs` machine snapshot
+//
+//   magic            b"SIRS"
+//   format           u16, FORMAT_VERSION
+//   isa              u16, ISA_VERSION of the machine
+//   program hash     u64, see program_hash
+//   pc               u64
+//   state            u8, 0 running, 1 interupted, 2 ended, 3 trapped followed by the trap
+//   fuel             u8 0 unmetered, or 1 followed by u64
+//   calculation      u64 count, then u64 values bottom first
+//   return           u64 count, then u64 values bottom first
+//   memory           u64 length, then the bytes
+//   heap             u64 length, then the bytes, format 2 on
+//   heap blocks      used, free and freed lists: u64 count, then offset u64 and size u64
+//   fp               u64, format 3 on
+//   crc              u32, crc32 of everything before
+//
+// all integers are little endian
+pub const MAGIC: [u8; 4] = *b"SIRS";
+pub const FORMAT_VERSION: u16 = 3;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedFormat(u16),
+    UnsupportedIsa(u16),
+    Truncated,
+    ChecksumMismatch,
+    Malformed,
+    // the snapshot was taken running a different program
+    ProgramMismatch { expected: u64, found: u64 },
+    // the stacks or memory do not fit the MachineConfig of the restoring machine
+    ExceedsLimits,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "i/o error: {e}"),
+            Self::BadMagic => write!(f, "not a machine snapshot, bad magic number"),
+            Self::UnsupportedFormat(v) => write!(
+                f,
+                "unsupported snapshot format version {v}, expected {FORMAT_VERSION}"
+            ),
+            Self::UnsupportedIsa(v) => write!(
+                f,
+                "snapshot needs isa version {v}, this machine supports up to {ISA_VERSION}"
+            ),
+            Self::Truncated => write!(f, "snapshot is truncated"),
+            Self::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            Self::Malformed => write!(f, "malformed snapshot"),
+            Self::ProgramMismatch { expected, found } => write!(
+                f,
+                "snapshot belongs to program {expected:#018x}, not {found:#018x}"
+            ),
+            Self::ExceedsLimits => write!(f, "snapshot exceeds the machine limits"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<Truncated> for SnapshotError {
+    fn from(_: Truncated) -> Self {
+        Self::Truncated
+    }
+}
+
+// execution state of a machine, host functions, limits and the cost model
+// belong to the host and are not part of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineSnapshot {
+    program_hash: u64,
+    pc: u64,
+    fp: u64,
+    state: MachineState,
+    fuel: Option<u64>,
+    calculation_stack: Vec<u64>,
+    return_stack: Vec<u64>,
+    memory: Vec<u8>,
+    heap: Heap,
+}
+
+// FNV-1a 64 over the code and data of a program
+pub fn program_hash(program: &ProgramMemory) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let len = |b: &[u8]| (b.len() as u64).to_le_bytes();
+    for part in [
+        &len(program.prog())[..],
+        program.prog(),
+        &len(program.data())[..],
+        program.data(),
+    ] {
+        for &b in part {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+impl Machine {
+    // `program` is the one the machine is running
+    pub fn snapshot(&self, program: &ProgramMemory) -> MachineSnapshot {
+        MachineSnapshot {
+            program_hash: program_hash(program),
+            pc: self.pc,
+            fp: self.fp,
+            state: self.state.clone(),
+            fuel: self.fuel,
+            calculation_stack: self.calculation_stack.as_slice().to_vec(),
+            return_stack: self.return_stack.as_slice().to_vec(),
+            memory: self.runtime_memory.as_slice().to_vec(),
+            // debug mode belongs to the config, not the state
+            heap: Heap {
+                debug: false,
+                ..self.runtime_memory.heap.clone()
+            },
+        }
+    }
+    // continue from `snapshot` running `program`, the machine is unchanged on error
+    pub fn restore(
+        &mut self,
+        snapshot: MachineSnapshot,
+        program: &ProgramMemory,
+    ) -> Result<(), SnapshotError> {
+        let found = program_hash(program);
+        if snapshot.program_hash != found {
+            return Err(SnapshotError::ProgramMismatch {
+                expected: snapshot.program_hash,
+                found,
+            });
+        }
+        let max = &self.config;
+        if max
+            .max_calculation_stack
+            .is_some_and(|m| snapshot.calculation_stack.len() > m)
+            || max
+                .max_return_stack
+                .is_some_and(|m| snapshot.return_stack.len() > m)
+            || max
+                .max_memory
+                .is_some_and(|m| (snapshot.memory.len() + snapshot.heap.raw.len()) as u64 > m)
+        {
+            return Err(SnapshotError::ExceedsLimits);
+        }
+        self.pc = snapshot.pc;
+        self.fp = snapshot.fp;
+        self.state = snapshot.state;
+        self.fuel = snapshot.fuel;
+        self.calculation_stack.replace(snapshot.calculation_stack);
+        self.return_stack.replace(snapshot.return_stack);
+        self.runtime_memory.replace(snapshot.memory);
+        self.runtime_memory.heap = Heap {
+            debug: self.config.debug_heap,
+            ..snapshot.heap
+        };
+        Ok(())
+    }
+}
+
+impl MachineSnapshot {
+    pub fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+    pub fn state(&self) -> &MachineState {
+        &self.state
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), SnapshotError> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&ISA_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.program_hash.to_le_bytes());
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        encode_state(&mut out, &self.state);
+        match self.fuel {
+            None => out.push(0),
+            Some(f) => {
+                out.push(1);
+                out.extend_from_slice(&f.to_le_bytes());
+            }
+        }
+        for stack in [&self.calculation_stack, &self.return_stack] {
+            out.extend_from_slice(&(stack.len() as u64).to_le_bytes());
+            for v in stack {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&(self.memory.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.memory);
+        out.extend_from_slice(&(self.heap.raw.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.heap.raw);
+        for blocks in [&self.heap.used, &self.heap.free, &self.heap.freed] {
+            out.extend_from_slice(&(blocks.len() as u64).to_le_bytes());
+            for (o, s) in blocks {
+                out.extend_from_slice(&o.to_le_bytes());
+                out.extend_from_slice(&s.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&self.fp.to_le_bytes());
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        writer.write_all(&out)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self, SnapshotError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut c = Cursor(&bytes);
+        if c.take(4)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let format = c.u16()?;
+        // format 1 has no heap, 2 no frame pointer
+        if format == 0 || format > FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedFormat(format));
+        }
+        let isa = c.u16()?;
+        if isa > ISA_VERSION {
+            return Err(SnapshotError::UnsupportedIsa(isa));
+        }
+        let Some(body_len) = bytes.len().checked_sub(4).filter(|l| *l >= 8) else {
+            return Err(SnapshotError::Truncated);
+        };
+        let (body, crc) = bytes.split_at(body_len);
+        if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        let mut c = Cursor(&body[8..]);
+        let program_hash = c.u64()?;
+        let pc = c.u64()?;
+        let state = decode_state(&mut c)?;
+        let fuel = match c.u8()? {
+            0 => None,
+            1 => Some(c.u64()?),
+            _ => return Err(SnapshotError::Malformed),
+        };
+        let calculation_stack = read_u64s(&mut c)?;
+        let return_stack = read_u64s(&mut c)?;
+        let len = read_len(&mut c)?;
+        let memory = c.take(len)?.to_vec();
+        let mut heap = Heap::default();
+        if format >= 2 {
+            let len = read_len(&mut c)?;
+            heap.raw = c.take(len)?.to_vec();
+            for blocks in [&mut heap.used, &mut heap.free, &mut heap.freed] {
+                for _ in 0..read_len(&mut c)? {
+                    let (offset, size) = (c.u64()?, c.u64()?);
+                    if blocks.insert(offset, size).is_some() {
+                        return Err(SnapshotError::Malformed);
+                    }
+                }
+            }
+            if !heap.is_consistent() {
+                return Err(SnapshotError::Malformed);
+            }
+        }
+        let fp = match format {
+            3.. => c.u64()?,
+            _ => 0,
+        };
+        if !c.is_empty() {
+            return Err(SnapshotError::Malformed);
+        }
+        Ok(Self {
+            program_hash,
+            pc,
+            fp,
+            state,
+            fuel,
+            calculation_stack,
+            return_stack,
+            memory,
+            heap,
+        })
+    }
+}
+
+fn encode_state(out: &mut Vec<u8>, state: &MachineState) {
+    match state {
+        MachineState::Running => out.push(0),
+        MachineState::Interupted => out.push(1),
+        MachineState::Ended => out.push(2),
+        MachineState::Trapped(t) => {
+            out.push(3);
+            encode_trap(out, t);
+        }
+    }
+}
+
+fn decode_state(c: &mut Cursor) -> Result<MachineState, SnapshotError> {
+    Ok(match c.u8()? {
+        0 => MachineState::Running,
+        1 => MachineState::Interupted,
+        2 => MachineState::Ended,
+        3 => MachineState::Trapped(decode_trap(c)?),
+        _ => return Err(SnapshotError::Malformed),
+    })
+}
+
+// trap: kind u8 followed by its fields, Host messages as length u32 and utf-8
+fn encode_trap(out: &mut Vec<u8>, trap: &Trap) {
+    let u64s: &[u64] = match trap {
+        Trap::StackUnderflow => &[0],
+        Trap::ReturnStackUnderflow => &[1],
+        Trap::InvalidOpcode { pc, byte } => &[2, *pc, *byte as u64],
+        Trap::ProgramOutOfBounds { pc } => &[3, *pc],
+        Trap::MemoryOutOfBounds { addr, width } => &[4, *addr, *width],
+        Trap::MemoryUnderflow { size } => &[5, *size],
+        Trap::DataOutOfBounds { addr, width } => &[6, *addr, *width],
+        Trap::DivideByZero => &[7],
+        Trap::UnknownHostFunction { id } => &[8, *id as u64],
+        Trap::Host(msg) => {
+            out.push(9);
+            out.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+            out.extend_from_slice(msg.as_bytes());
+            return;
+        }
+        Trap::OutOfFuel { cost, fuel } => &[10, *cost, *fuel],
+        Trap::CalculationStackOverflow => &[11],
+        Trap::ReturnStackOverflow => &[12],
+        Trap::OutOfMemory { size } => &[13, *size],
+        Trap::DoubleFree { addr } => &[14, *addr],
+        Trap::InvalidFree { addr } => &[15, *addr],
+        Trap::UseAfterFree { addr } => &[16, *addr],
+    };
+    out.push(u64s[0] as u8);
+    for v in &u64s[1..] {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn decode_trap(c: &mut Cursor) -> Result<Trap, SnapshotError> {
+    Ok(match c.u8()? {
+        0 => Trap::StackUnderflow,
+        1 => Trap::ReturnStackUnderflow,
+        2 => Trap::InvalidOpcode {
+            pc: c.u64()?,
+            byte: c.u64()? as u8,
+        },
+        3 => Trap::ProgramOutOfBounds { pc: c.u64()? },
+        4 => Trap::MemoryOutOfBounds {
+            addr: c.u64()?,
+            width: c.u64()?,
+        },
+        5 => Trap::MemoryUnderflow { size: c.u64()? },
+        6 => Trap::DataOutOfBounds {
+            addr: c.u64()?,
+            width: c.u64()?,
+        },
+        7 => Trap::DivideByZero,
+        8 => Trap::UnknownHostFunction {
+            id: c.u64()? as u16,
+        },
+        9 => {
+            let len = u32::from_le_bytes(c.take(4)?.try_into().unwrap()) as usize;
+            let msg =
+                String::from_utf8(c.take(len)?.to_vec()).map_err(|_| SnapshotError::Malformed)?;
+            Trap::Host(msg)
+        }
+        10 => Trap::OutOfFuel {
+            cost: c.u64()?,
+            fuel: c.u64()?,
+        },
+        11 => Trap::CalculationStackOverflow,
+        12 => Trap::ReturnStackOverflow,
+        13 => Trap::OutOfMemory { size: c.u64()? },
+        14 => Trap::DoubleFree { addr: c.u64()? },
+        15 => Trap::InvalidFree { addr: c.u64()? },
+        16 => Trap::UseAfterFree { addr: c.u64()? },
+        _ => return Err(SnapshotError::Malformed),
+    })
+}
+
+// a u64 length that must fit in memory
+fn read_len(c: &mut Cursor) -> Result<usize, Truncated> {
+    usize::try_from(c.u64()?).map_err(|_| Truncated)
+}
+
+fn read_u64s(c: &mut Cursor) -> Result<Vec<u64>, Truncated> {
+    let count = read_len(c)?;
+    let bytes = c.take(count.checked_mul(8).ok_or(Truncated)?)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::MachineConfig;
+
+    // stops at the Interupt with values on both stacks, memory, heap and a frame
+    const PROGRAM: &str = "
+        Alloc 16
+        Im8 5
+        Im8 0
+        Store64
+        Im8 24
+        Malloc
+        Enter 8
+        Im8 3
+        ToR
+        Interupt
+        FromR
+        Add
+        Leave";
+
+    fn interrupted() -> (ProgramMemory, Machine) {
+        let program = assemble(PROGRAM).unwrap();
+        let mut machine = Machine::new();
+        machine.set_fuel(Some(1000));
+        machine.run(&program, None);
+        assert_eq!(machine.state(), MachineState::Interupted);
+        (program, machine)
+    }
+
+    fn encode(snapshot: &MachineSnapshot) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    // rewrite as `format` by dropping the fields it does not have
+    fn downgrade(bytes: &[u8], format: u16) -> Vec<u8> {
+        let drop = match format {
+            // heap length and block counts of an empty heap and fp
+            1 => 8 + 3 * 8 + 8,
+            _ => 8,
+        };
+        let mut out = bytes[..bytes.len() - 4 - drop].to_vec();
+        out[4..6].copy_from_slice(&format.to_le_bytes());
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn round_trip_continues_the_same() {
+        let (program, mut machine) = interrupted();
+        let snapshot = machine.snapshot(&program);
+        let decoded = MachineSnapshot::read_from(&encode(&snapshot)[..]).unwrap();
+        assert_eq!(decoded, snapshot);
+
+        let mut restored = Machine::new();
+        restored.restore(decoded, &program).unwrap();
+        assert_eq!(restored.fp(), 16);
+        assert_eq!(restored.heap_memory().len(), 24);
+        for m in [&mut machine, &mut restored] {
+            m.resume();
+            m.run(&program, None);
+        }
+        assert_eq!(restored.state(), MachineState::Ended);
+        assert_eq!(restored.snapshot(&program), machine.snapshot(&program));
+    }
+
+    #[test]
+    fn round_trip_keeps_traps_and_freed_blocks() {
+        let program = assemble("Im8 8\n Malloc\n Dup\n Free\n Free").unwrap();
+        let config = MachineConfig {
+            debug_heap: true,
+            ..Default::default()
+        };
+        let mut machine = Machine::with_config(config.clone());
+        machine.run(&program, None);
+        let snapshot = machine.snapshot(&program);
+        let decoded = MachineSnapshot::read_from(&encode(&snapshot)[..]).unwrap();
+        assert_eq!(decoded, snapshot);
+        assert!(matches!(
+            decoded.state(),
+            MachineState::Trapped(Trap::DoubleFree { .. })
+        ));
+        let mut restored = Machine::with_config(config);
+        restored.restore(decoded, &program).unwrap();
+        assert_eq!(restored.snapshot(&program), snapshot);
+    }
+
+    #[test]
+    fn reads_older_formats() {
+        let program = assemble("Alloc 8\n Im8 1\n ToR\n Interupt").unwrap();
+        let mut machine = Machine::new();
+        machine.run(&program, None);
+        let snapshot = machine.snapshot(&program);
+        let bytes = encode(&snapshot);
+        for format in [1, 2] {
+            let old = MachineSnapshot::read_from(&downgrade(&bytes, format)[..]).unwrap();
+            assert_eq!(old, snapshot);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let (program, machine) = interrupted();
+        let bytes = encode(&machine.snapshot(&program));
+        let read = |b: &[u8]| MachineSnapshot::read_from(b).unwrap_err();
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(read(&bad), SnapshotError::BadMagic));
+        for format in [0, FORMAT_VERSION + 1] {
+            let mut bad = bytes.clone();
+            bad[4..6].copy_from_slice(&format.to_le_bytes());
+            assert!(matches!(read(&bad), SnapshotError::UnsupportedFormat(f) if f == format));
+        }
+        let mut bad = bytes.clone();
+        bad[6..8].copy_from_slice(&(ISA_VERSION + 1).to_le_bytes());
+        assert!(matches!(read(&bad), SnapshotError::UnsupportedIsa(_)));
+        assert!(matches!(read(&bytes[..9]), SnapshotError::Truncated));
+        let mut bad = bytes.clone();
+        bad[20] ^= 1;
+        assert!(matches!(read(&bad), SnapshotError::ChecksumMismatch));
+        // a valid checksum over a body with a missing field
+        let mut short = bytes[..bytes.len() - 12].to_vec();
+        let crc = crc32(&short);
+        short.extend_from_slice(&crc.to_le_bytes());
+        assert!(matches!(read(&short), SnapshotError::Truncated));
+    }
+
+    #[test]
+    fn rejects_inconsistent_heap_blocks() {
+        let (program, machine) = interrupted();
+        let snapshot = machine.snapshot(&program);
+        assert_eq!(snapshot.heap.used, [(0, 24)].into());
+        let tables: [fn(&mut Heap); 5] = [
+            // overlapping
+            |h| {
+                h.used.insert(8, 8);
+            },
+            // both used and free
+            |h| {
+                h.free.insert(0, 24);
+            },
+            // past the end
+            |h| {
+                h.used.insert(0, 32);
+            },
+            // adjacent free blocks
+            |h| {
+                h.used.clear();
+                h.free.extend([(0, 8), (8, 16)]);
+            },
+            // a gap
+            |h| {
+                h.used.insert(0, 16);
+            },
+        ];
+        for change in tables {
+            let mut bad = snapshot.clone();
+            change(&mut bad.heap);
+            assert!(matches!(
+                MachineSnapshot::read_from(&encode(&bad)[..]),
+                Err(SnapshotError::Malformed)
+            ));
+        }
+    }
+
+    #[test]
+    fn restore_checks_program_and_limits() {
+        let (program, machine) = interrupted();
+        let snapshot = machine.snapshot(&program);
+
+        let other = assemble("Nop").unwrap();
+        let mut m = Machine::new();
+        assert!(matches!(
+            m.restore(snapshot.clone(), &other),
+            Err(SnapshotError::ProgramMismatch { .. })
+        ));
+        let limits = [
+            MachineConfig {
+                max_calculation_stack: Some(0),
+                ..Default::default()
+            },
+            MachineConfig {
+                max_return_stack: Some(1),
+                ..Default::default()
+            },
+            // 24 bytes of memory and 24 of heap
+            MachineConfig {
+                max_memory: Some(47),
+                ..Default::default()
+            },
+        ];
+        for config in limits {
+            let mut m = Machine::with_config(config);
+            assert!(matches!(
+                m.restore(snapshot.clone(), &program),
+                Err(SnapshotError::ExceedsLimits)
+            ));
+            assert_eq!(m.pc(), 0);
+        }
+        let mut m = Machine::with_config(MachineConfig {
+            max_memory: Some(48),
+            ..Default::default()
+        });
+        m.restore(snapshot, &program).unwrap();
+    }
+}
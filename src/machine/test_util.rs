@@ -0,0 +1,14 @@
+use crate::assembler::assemble;
+use crate::machine::{Machine, MachineConfig};
+
+// assemble `src` and run it on a fresh machine until it stops
+pub(crate) fn run_with(config: MachineConfig, src: &str) -> Machine {
+    let program = assemble(src).unwrap();
+    let mut machine = Machine::with_config(config);
+    machine.run(&program, None);
+    machine
+}
+
+pub(crate) fn run(src: &str) -> Machine {
+    run_with(MachineConfig::default(), src)
+}
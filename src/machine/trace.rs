@@ -0,0 +1,415 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::instruction::Instructions;
+use crate::machine::program_memory::ProgramMemory;
+use crate::machine::{Machine, Trap};
+
+// Execution tracing.
+//
+// A tracer set with `Machine::set_tracer` sees one TraceEvent per executed
+// instruction. Tracing runs the byte interpreter, `run_decoded` falls back
+// to it while a tracer is set.
+
+// number of calculation stack values recorded before and after an instruction
+pub const TRACE_STACK_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub addr: u64,
+    pub width: u64,
+    // little endian of the first 8 bytes written
+    pub old: u64,
+    pub new: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub pc: u64,
+    // None when the byte at pc is not an instruction
+    pub ins: Option<Instructions>,
+    pub im: Option<u64>,
+    // up to TRACE_STACK_DEPTH values of the calculation stack, top last
+    pub before: Vec<u64>,
+    pub after: Vec<u64>,
+    pub writes: Vec<MemoryWrite>,
+    pub trap: Option<Trap>,
+}
+
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent);
+    // flush buffered output, reports the first error writing events
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&TraceEvent)> Tracer for F {
+    fn trace(&mut self, event: &TraceEvent) {
+        self(event)
+    }
+}
+
+impl fmt::Debug for dyn Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tracer")
+    }
+}
+
+// one json object per line:
+// {"pc":2,"ins":"Call","im":37,"before":[5],"after":[5],"writes":[],"trap":null}
+// writes are {"addr":0,"width":8,"old":0,"new":1}, a trap is its message
+#[derive(Debug)]
+pub struct JsonLinesTracer<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+}
+
+impl<W: Write> Tracer for JsonLinesTracer<W> {
+    fn trace(&mut self, e: &TraceEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+        let list = |v: &[u64]| {
+            let items: Vec<String> = v.iter().map(|x| x.to_string()).collect();
+            format!("[{}]", items.join(","))
+        };
+        let writes: Vec<String> = e
+            .writes
+            .iter()
+            .map(|w| {
+                format!(
+                    r#"{{"addr":{},"width":{},"old":{},"new":{}}}"#,
+                    w.addr, w.width, w.old, w.new
+                )
+            })
+            .collect();
+        let line = format!(
+            r#"{{"pc":{},"ins":{},"im":{},"before":{},"after":{},"writes":[{}],"trap":{}}}"#,
+            e.pc,
+            opt(e.ins.as_ref().map(|i| format!("\"{}\"", i.mnemonic()))),
+            opt(e.im.map(|i| i.to_string())),
+            list(&e.before),
+            list(&e.after),
+            writes.join(","),
+            opt(e.trap.as_ref().map(|t| json_string(&t.to_string()))),
+        );
+        if let Err(err) = writeln!(self.writer, "{line}") {
+            self.error = Some(err);
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+// compact binary trace
+//
+// header
+//   magic         b"SIRT"
+//   format        u16, BINARY_TRACE_VERSION
+// per event
+//   flags         u8, 1 instruction decoded, 2 has immediate, 4 trapped
+//   pc            u64
+//   opcode        u8, 0 when not decoded
+//   immediate     u64, if flagged
+//   before        u8 count, then u64 values
+//   after         u8 count, then u64 values
+//   writes        u32 count, then addr u64, width u64, old u64, new u64
+//   trap          u32 length and utf-8 message, if flagged
+//
+// all integers are little endian
+pub const BINARY_TRACE_MAGIC: [u8; 4] = *b"SIRT";
+pub const BINARY_TRACE_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub struct BinaryTracer<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&BINARY_TRACE_MAGIC)?;
+        writer.write_all(&BINARY_TRACE_VERSION.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            error: None,
+        })
+    }
+}
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn trace(&mut self, e: &TraceEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut out = Vec::new();
+        let flags =
+            e.ins.is_some() as u8 | (e.im.is_some() as u8) << 1 | (e.trap.is_some() as u8) << 2;
+        out.push(flags);
+        out.extend_from_slice(&e.pc.to_le_bytes());
+        out.push(e.ins.as_ref().map_or(0, |i| i.opcode()));
+        if let Some(im) = e.im {
+            out.extend_from_slice(&im.to_le_bytes());
+        }
+        for values in [&e.before, &e.after] {
+            out.push(values.len() as u8);
+            for v in values {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&(e.writes.len() as u32).to_le_bytes());
+        for w in &e.writes {
+            for v in [w.addr, w.width, w.old, w.new] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        if let Some(t) = &e.trap {
+            let msg = t.to_string();
+            out.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+            out.extend_from_slice(msg.as_bytes());
+        }
+        if let Err(err) = self.writer.write_all(&out) {
+            self.error = Some(err);
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+impl Machine {
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+    // execute with the tracer watching
+    pub(crate) fn execute_traced(&mut self, program: &ProgramMemory) -> Result<(), Trap> {
+        let pc = self.pc;
+        let ins = program.get_opcode_at(pc).ok();
+        let im = ins
+            .as_ref()
+            .filter(|i| i.im_size() > 0)
+            .and_then(|i| program.get_im_at(pc + 1, i.im_size()).ok());
+        let before = self.stack_top();
+        self.runtime_memory.trace_writes = Some(Vec::new());
+        let res = self.execute(program);
+        let writes = self.runtime_memory.trace_writes.take().unwrap_or_default();
+        let event = TraceEvent {
+            pc,
+            ins,
+            im,
+            before,
+            after: self.stack_top(),
+            writes,
+            trap: res.clone().err(),
+        };
+        if let Some(t) = &mut self.tracer {
+            t.trace(&event);
+        }
+        res
+    }
+    fn stack_top(&self) -> Vec<u64> {
+        let s = self.calculation_stack.as_slice();
+        s[s.len().saturating_sub(TRACE_STACK_DEPTH)..].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::MachineState;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn traced(program: &ProgramMemory) -> (Machine, Vec<TraceEvent>) {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = events.clone();
+        let mut m = Machine::new();
+        m.set_tracer(Some(Box::new(move |e: &TraceEvent| {
+            sink.borrow_mut().push(e.clone())
+        })));
+        m.run(program, None);
+        let events = events.take();
+        (m, events)
+    }
+
+    fn store_event() -> TraceEvent {
+        TraceEvent {
+            pc: 7,
+            ins: Some(Instructions::Store64),
+            im: None,
+            before: vec![1, 0],
+            after: vec![],
+            writes: vec![MemoryWrite {
+                addr: 0,
+                width: 8,
+                old: 0,
+                new: 1,
+            }],
+            trap: None,
+        }
+    }
+
+    #[test]
+    fn closures_see_every_instruction() {
+        let (m, events) = traced(
+            &assemble("Alloc 8\n Im8 1\n Im8 0\n Store64\n Im8 1\n Im8 2\n Im8 3\n Im8 4\n Im8 5")
+                .unwrap(),
+        );
+        assert_eq!(m.state(), MachineState::Ended);
+        assert!(m.is_tracing());
+        let pcs: Vec<u64> = events.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, [0, 2, 4, 6, 7, 9, 11, 13, 15]);
+        assert_eq!(events[1].im, Some(1));
+        assert_eq!(events[3].before, [1, 0]);
+        assert_eq!(
+            events[3].writes,
+            [MemoryWrite {
+                addr: 0,
+                width: 8,
+                old: 0,
+                new: 1
+            }]
+        );
+        // only the top TRACE_STACK_DEPTH values are kept
+        assert_eq!(events[8].after, [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn traps_and_bad_opcodes_are_traced() {
+        let (_, events) = traced(&assemble("Im8 0\n Im8 1\n Div").unwrap());
+        let last = events.last().unwrap();
+        assert_eq!(last.trap, Some(Trap::DivideByZero));
+        assert_eq!(last.before, [0, 1]);
+
+        let (mut m, events) = traced(&ProgramMemory::new(vec![0xff], vec![]));
+        assert!(m.take_tracer().is_some());
+        assert!(!m.is_tracing());
+        assert_eq!((events[0].ins.as_ref(), events[0].im), (None, None));
+        assert!(events[0].trap.is_some());
+    }
+
+    #[test]
+    fn json_lines_format() {
+        let mut t = JsonLinesTracer::new(Vec::new());
+        t.trace(&store_event());
+        t.trace(&TraceEvent {
+            pc: 3,
+            ins: None,
+            im: Some(5),
+            before: vec![],
+            after: vec![],
+            writes: vec![],
+            trap: Some(Trap::Host("bad \"x\"\n".to_string())),
+        });
+        t.flush().unwrap();
+        let text = String::from_utf8(t.writer).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"pc":7,"ins":"Store64","im":null,"before":[1,0],"after":[],"writes":[{"addr":0,"width":8,"old":0,"new":1}],"trap":null}"#
+        );
+        assert!(lines[1].starts_with(
+            r#"{"pc":3,"ins":null,"im":5,"before":[],"after":[],"writes":[],"trap":""#
+        ));
+        assert!(lines[1].ends_with(r#"bad \"x\"\n"}"#), "{}", lines[1]);
+        assert_eq!(json_string("\u{1}\\"), r#""\u0001\\""#);
+    }
+
+    #[test]
+    fn binary_layout() {
+        let mut t = BinaryTracer::new(Vec::new()).unwrap();
+        t.trace(&store_event());
+        t.trace(&TraceEvent {
+            pc: 1,
+            ins: Some(Instructions::Div),
+            im: None,
+            before: vec![],
+            after: vec![],
+            writes: vec![],
+            trap: Some(Trap::DivideByZero),
+        });
+        let mut expected = b"SIRT".to_vec();
+        expected.extend(BINARY_TRACE_VERSION.to_le_bytes());
+        expected.push(1);
+        expected.extend(7u64.to_le_bytes());
+        expected.push(Instructions::Store64.opcode());
+        expected.push(2);
+        expected.extend(1u64.to_le_bytes());
+        expected.extend(0u64.to_le_bytes());
+        expected.push(0);
+        expected.extend(1u32.to_le_bytes());
+        for v in [0u64, 8, 0, 1] {
+            expected.extend(v.to_le_bytes());
+        }
+        let msg = Trap::DivideByZero.to_string();
+        expected.push(1 | 4);
+        expected.extend(1u64.to_le_bytes());
+        expected.push(Instructions::Div.opcode());
+        expected.extend([0, 0]);
+        expected.extend(0u32.to_le_bytes());
+        expected.extend((msg.len() as u32).to_le_bytes());
+        expected.extend(msg.as_bytes());
+        assert_eq!(t.writer, expected);
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_errors_are_reported_by_flush() {
+        assert!(BinaryTracer::new(Broken).is_err());
+        let mut t = JsonLinesTracer::new(Broken);
+        t.trace(&store_event());
+        t.trace(&store_event());
+        assert_eq!(t.flush().unwrap_err().to_string(), "broken");
+        assert!(t.flush().is_ok());
+    }
+}
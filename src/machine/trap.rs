@@ -0,0 +1,94 @@
+use std::fmt;
+
+// a fault raised while executing a program,
+// the machine stops in MachineState::Trapped when one happens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trap {
+    // calculation stack has not enough values for the instruction
+    StackUnderflow,
+    // return stack is empty when popping
+    ReturnStackUnderflow,
+    // the byte at pc is not a known opcode
+    InvalidOpcode { pc: u64, byte: u8 },
+    // pc or an immediate operand is outside the program
+    ProgramOutOfBounds { pc: u64 },
+    // runtime memory access outside allocated memory
+    MemoryOutOfBounds { addr: u64, width: u64 },
+    // dealloc more bytes than allocated
+    MemoryUnderflow { size: u64 },
+    // static data access outside the data segment
+    DataOutOfBounds { addr: u64, width: u64 },
+    // integer division or modulo by zero
+    DivideByZero,
+    // HostCall with an id nothing is registered for
+    UnknownHostFunction { id: u16 },
+    // raised by a host function
+    Host(String),
+    // push beyond MachineConfig::max_calculation_stack
+    CalculationStackOverflow,
+    // push beyond MachineConfig::max_return_stack
+    ReturnStackOverflow,
+    // alloc of `size` bytes beyond MachineConfig::max_memory
+    OutOfMemory { size: u64 },
+    // the instruction costs more fuel than is left, pc stays on it
+    OutOfFuel { cost: u64, fuel: u64 },
+    // Free of a block freed before, only detected with MachineConfig::debug_heap
+    DoubleFree { addr: u64 },
+    // Free or Realloc of an address that is not a live heap block
+    InvalidFree { addr: u64 },
+    // access to a freed heap block, only detected with MachineConfig::debug_heap
+    UseAfterFree { addr: u64 },
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::StackUnderflow => write!(f, "calculation stack underflowed"),
+            Trap::ReturnStackUnderflow => write!(f, "return stack underflowed"),
+            Trap::InvalidOpcode { pc, byte } => {
+                write!(f, "invalid opcode {byte:#04x} at {pc}")
+            }
+            Trap::ProgramOutOfBounds { pc } => {
+                write!(f, "program memory read out of bounds at {pc}")
+            }
+            Trap::MemoryOutOfBounds { addr, width } => {
+                write!(
+                    f,
+                    "runtime memory access of {width} bytes at {addr} out of bounds"
+                )
+            }
+            Trap::MemoryUnderflow { size } => {
+                write!(f, "dealloc of {size} bytes underflowed runtime memory")
+            }
+            Trap::DataOutOfBounds { addr, width } => {
+                write!(
+                    f,
+                    "static data access of {width} bytes at {addr} out of bounds"
+                )
+            }
+            Trap::DivideByZero => write!(f, "integer division by zero"),
+            Trap::UnknownHostFunction { id } => write!(f, "no host function with id {id}"),
+            Trap::Host(msg) => write!(f, "host function failed: {msg}"),
+            Trap::CalculationStackOverflow => write!(f, "calculation stack overflowed"),
+            Trap::ReturnStackOverflow => write!(f, "return stack overflowed"),
+            Trap::OutOfMemory { size } => {
+                write!(f, "alloc of {size} bytes exceeds the memory limit")
+            }
+            Trap::OutOfFuel { cost, fuel } => {
+                write!(
+                    f,
+                    "out of fuel, instruction costs {cost} but {fuel} is left"
+                )
+            }
+            Trap::DoubleFree { addr } => write!(f, "heap block at {addr:#x} freed twice"),
+            Trap::InvalidFree { addr } => {
+                write!(f, "{addr:#x} is not the address of a heap block")
+            }
+            Trap::UseAfterFree { addr } => {
+                write!(f, "access to freed heap memory at {addr:#x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
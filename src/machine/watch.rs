@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+
+// runtime memory watchpoints, checked on every load and store of the
+// program and of host functions
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchAction {
+    // only add a WatchEvent
+    Record,
+    // also stop the machine in MachineState::Interupted after the instruction
+    Interrupt,
+}
+
+// the byte range start..start + len, triggered by any overlapping access
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u64,
+    pub len: u64,
+    pub read: bool,
+    pub write: bool,
+    pub action: WatchAction,
+}
+
+impl Watchpoint {
+    pub fn reads(start: u64, len: u64) -> Self {
+        Self {
+            start,
+            len,
+            read: true,
+            write: false,
+            action: WatchAction::Record,
+        }
+    }
+    pub fn writes(start: u64, len: u64) -> Self {
+        Self {
+            read: false,
+            write: true,
+            ..Self::reads(start, len)
+        }
+    }
+    pub fn accesses(start: u64, len: u64) -> Self {
+        Self {
+            write: true,
+            ..Self::reads(start, len)
+        }
+    }
+    pub fn interrupting(self) -> Self {
+        Self {
+            action: WatchAction::Interrupt,
+            ..self
+        }
+    }
+    fn triggers(&self, access: Access, addr: u64, width: u64) -> bool {
+        let wanted = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        wanted
+            && addr < self.start.saturating_add(self.len)
+            && self.start < addr.saturating_add(width)
+    }
+}
+
+// one watched access, values are little endian of the first 8 bytes accessed,
+// a read has old == new
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    // the watchpoint that triggered
+    pub id: usize,
+    // instruction doing the access, a HostCall for host function accesses
+    pub pc: u64,
+    pub access: Access,
+    pub addr: u64,
+    pub width: u64,
+    pub old: u64,
+    pub new: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Watches {
+    points: BTreeMap<usize, Watchpoint>,
+    next_id: usize,
+    events: Vec<WatchEvent>,
+    // an Interrupt watchpoint triggered since the last take_interrupt
+    interrupt: bool,
+    // pc of the running instruction, kept up to date by the machine
+    pub(crate) pc: u64,
+}
+
+impl Watches {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+    pub(crate) fn add(&mut self, w: Watchpoint) -> usize {
+        // ids start at 1
+        self.next_id += 1;
+        let id = self.next_id;
+        self.points.insert(id, w);
+        id
+    }
+    pub(crate) fn remove(&mut self, id: usize) -> Option<Watchpoint> {
+        self.points.remove(&id)
+    }
+    pub(crate) fn points(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.points.iter().map(|(id, w)| (*id, w))
+    }
+    pub(crate) fn events(&self) -> &[WatchEvent] {
+        &self.events
+    }
+    pub(crate) fn take_events(&mut self) -> Vec<WatchEvent> {
+        std::mem::take(&mut self.events)
+    }
+    pub(crate) fn take_interrupt(&mut self) -> bool {
+        std::mem::replace(&mut self.interrupt, false)
+    }
+    // record an access of `old.len()` bytes at `addr` that leaves `new` there
+    pub(crate) fn hit(&mut self, access: Access, addr: u64, old: &[u8], new: &[u8]) {
+        let width = old.len() as u64;
+        for (id, w) in &self.points {
+            if !w.triggers(access, addr, width) {
+                continue;
+            }
+            self.events.push(WatchEvent {
+                id: *id,
+                pc: self.pc,
+                access,
+                addr,
+                width,
+                old: le_u64(old),
+                new: le_u64(new),
+            });
+            self.interrupt |= w.action == WatchAction::Interrupt;
+        }
+    }
+}
+
+pub(crate) fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let n = bytes.len().min(8);
+    buf[..n].copy_from_slice(&bytes[..n]);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::machine::{Machine, MachineState};
+
+    #[test]
+    fn triggers_on_overlapping_accesses() {
+        let w = Watchpoint::writes(8, 4);
+        assert!(w.triggers(Access::Write, 11, 1));
+        assert!(w.triggers(Access::Write, 4, 8));
+        assert!(!w.triggers(Access::Write, 12, 8));
+        assert!(!w.triggers(Access::Write, 0, 8));
+        assert!(!w.triggers(Access::Read, 8, 4));
+        assert!(Watchpoint::reads(8, 4).triggers(Access::Read, 8, 1));
+        let all = Watchpoint::accesses(u64::MAX - 1, u64::MAX);
+        assert!(all.triggers(Access::Read, u64::MAX - 2, 8));
+        assert!(all.triggers(Access::Write, u64::MAX - 1, 1));
+        assert_eq!(all.action, WatchAction::Record);
+        assert_eq!(all.interrupting().action, WatchAction::Interrupt);
+    }
+
+    #[test]
+    fn ids_start_at_one_and_are_not_reused() {
+        let mut watches = Watches::default();
+        assert!(watches.is_empty());
+        assert_eq!(watches.add(Watchpoint::reads(0, 1)), 1);
+        assert_eq!(watches.add(Watchpoint::writes(0, 1)), 2);
+        assert_eq!(watches.remove(1), Some(Watchpoint::reads(0, 1)));
+        assert_eq!(watches.remove(1), None);
+        assert_eq!(watches.add(Watchpoint::reads(0, 1)), 3);
+        let ids: Vec<usize> = watches.points().map(|(id, _)| id).collect();
+        assert_eq!(ids, [2, 3]);
+    }
+
+    #[test]
+    fn records_events_with_old_and_new_values() {
+        let program = assemble(
+            "Alloc 16\n Im16 0x1234\n Im8 8\n Store64\n Im8 9\n Load8\n Im8 7\n Im8 0\n Store8",
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        let writes = m.watch(Watchpoint::writes(8, 8));
+        let reads = m.watch(Watchpoint::reads(8, 8));
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(
+            m.watch_events(),
+            [
+                WatchEvent {
+                    id: writes,
+                    pc: 7,
+                    access: Access::Write,
+                    addr: 8,
+                    width: 8,
+                    old: 0,
+                    new: 0x1234,
+                },
+                WatchEvent {
+                    id: reads,
+                    pc: 10,
+                    access: Access::Read,
+                    addr: 9,
+                    width: 1,
+                    old: 0x12,
+                    new: 0x12,
+                },
+            ]
+        );
+        assert_eq!(m.take_watch_events().len(), 2);
+        assert!(m.watch_events().is_empty());
+    }
+
+    #[test]
+    fn interrupting_watchpoints_stop_after_the_access() {
+        // pc is left on the instruction after the store
+        let program =
+            assemble("Alloc 8\n Im8 1\n Im8 0\n Store8\n Im8 2\n Im8 0\n Store8").unwrap();
+        let mut m = Machine::new();
+        let id = m.watch(Watchpoint::writes(0, 1).interrupting());
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Interupted);
+        assert_eq!(m.pc(), 7);
+        assert_eq!(m.memory()[0], 1);
+        assert_eq!(m.unwatch(id), Some(Watchpoint::writes(0, 1).interrupting()));
+        assert!(m.watchpoints().is_empty());
+        m.resume();
+        m.run(&program, None);
+        assert_eq!(m.state(), MachineState::Ended);
+        assert_eq!(m.memory()[0], 2);
+        assert_eq!(m.watch_events().len(), 1);
+    }
+}
@@ -3,16 +3,19 @@ use stackir::machine::program_memory::ProgramMemory;
 use stackir::machine::{Machine, MachineState};
 
 fn main() {
+    use stackir::instruction::program_builder::ProgramBuilder;
     use stackir::instruction::program_maker::ProgramMaker;
-    let mut ins = Vec::new();
+    let mut ins = ProgramBuilder::new();
+    let (start, halt) = (ins.label(), ins.label());
     ins
-        // jump to 1+8+1=10
-        .add_ins(I::J) // 0
-        .add_im64(10) // 1
+        // jump to start
+        .add_branch(I::J, start)
         // interupt
-        .add_ins(I::Interupt) // 9
+        .bind(halt)
+        .add_ins(I::Interupt)
         // push 12
-        .add_ins(I::Im8) // 10
+        .bind(start)
+        .add_ins(I::Im8)
         .add_im8(12)
         // push 34
         .add_ins(I::Im8)
@@ -50,17 +53,21 @@ fn main() {
         // dealloc 3 bytes
         .add_ins(I::Dealloc)
         .add_im8(3)
-        // jump to 9, interupt the program
-        .add_ins(I::Im8)
-        .add_im8(9)
+        // jump to halt, interupt the program
+        .add_ins(I::Im64)
+        .add_im_label(halt)
         .add_ins(I::Ja);
+    let ins = ins.finish().expect("all labels are bound");
 
     println!("{ins:?}");
     let p = ProgramMemory::new(ins, Vec::new());
     let mut m = Machine::new();
     while let MachineState::Running = m.state() {
         println!("{m:?}"); // print the state before fetching instruction
-        m.run_program(&p); // fetch instruction, run and increase pc
+        if let Err(t) = m.run_program(&p) {
+            // fetch instruction, run and increase pc
+            println!("trapped: {t}");
+        }
     }
     println!("{m:?}"); // print end state
 }
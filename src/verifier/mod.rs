@@ -0,0 +1,256 @@
+pub mod stack_depth;
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::disassembler::{disassemble, Decoded, DecodedInstruction};
+use crate::instruction::Instructions as I;
+use crate::machine::program_memory::ProgramMemory;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyIssue {
+    InvalidOpcode { offset: u64, byte: u8 },
+    // the immediate runs past the end of the program
+    TruncatedImmediate { offset: u64, ins: I },
+    // branch into the middle of an instruction or past the end
+    BadBranchTarget { offset: u64, target: u64 },
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpcode { offset, byte } => {
+                write!(f, "{offset:#x}: invalid opcode {byte:#04x}")
+            }
+            Self::TruncatedImmediate { offset, ins } => {
+                write!(
+                    f,
+                    "{offset:#x}: immediate of {} is truncated",
+                    ins.mnemonic()
+                )
+            }
+            Self::BadBranchTarget { offset, target } => {
+                write!(
+                    f,
+                    "{offset:#x}: branch target {target:#x} is not an instruction"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "program failed verification:")?;
+        for i in &self.issues {
+            write!(f, "\n  {i}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// a program whose every instruction decodes and whose direct branches land on instructions
+#[derive(Debug)]
+pub struct VerifiedProgram<'a> {
+    program: &'a ProgramMemory,
+    instructions: Vec<DecodedInstruction>,
+    unreachable: Vec<u64>,
+}
+
+impl<'a> VerifiedProgram<'a> {
+    pub fn program(&self) -> &'a ProgramMemory {
+        self.program
+    }
+    pub fn instructions(&self) -> &[DecodedInstruction] {
+        &self.instructions
+    }
+    // offsets of instructions no control flow path reaches
+    pub fn unreachable(&self) -> &[u64] {
+        &self.unreachable
+    }
+    // index into instructions() of the instruction at `offset`
+    pub fn index_of(&self, offset: u64) -> Option<usize> {
+        self.instructions
+            .binary_search_by_key(&offset, |d| d.offset)
+            .ok()
+    }
+}
+
+pub fn verify(program: &ProgramMemory) -> Result<VerifiedProgram<'_>, VerifyError> {
+    let instructions = disassemble(program);
+    let end = program.prog_len() as u64;
+    let boundaries: BTreeSet<u64> = instructions.iter().map(|d| d.offset).collect();
+    let is_target = |t: u64| t == end || boundaries.contains(&t);
+
+    let mut issues = Vec::new();
+    for d in &instructions {
+        match &d.kind {
+            Decoded::InvalidOpcode(byte) => issues.push(VerifyIssue::InvalidOpcode {
+                offset: d.offset,
+                byte: *byte,
+            }),
+            Decoded::Truncated(ins) => issues.push(VerifyIssue::TruncatedImmediate {
+                offset: d.offset,
+                ins: ins.clone(),
+            }),
+            Decoded::Instruction { ins, im: Some(t) }
+                if ins.is_direct_branch() && !is_target(*t) =>
+            {
+                issues.push(VerifyIssue::BadBranchTarget {
+                    offset: d.offset,
+                    target: *t,
+                })
+            }
+            Decoded::Instruction { .. } => {}
+        }
+    }
+    if !issues.is_empty() {
+        return Err(VerifyError { issues });
+    }
+
+    let mut verified = VerifiedProgram {
+        program,
+        instructions,
+        unreachable: Vec::new(),
+    };
+    let reached = reachable(&verified);
+    verified.unreachable = verified
+        .instructions
+        .iter()
+        .zip(reached)
+        .filter(|(_, r)| !r)
+        .map(|(d, _)| d.offset)
+        .collect();
+    Ok(verified)
+}
+
+// control flow successors of an instruction, computed targets are left out
+pub(crate) fn successors(d: &DecodedInstruction) -> Vec<u64> {
+    let Some((ins, im)) = d.instruction() else {
+        return Vec::new();
+    };
+    let mut res = Vec::new();
+    if !matches!(ins, I::J | I::Ja | I::Ret) {
+        res.push(d.next_offset());
+    }
+    if let (true, Some(t)) = (ins.is_direct_branch(), im) {
+        res.push(t);
+    }
+    res
+}
+
+// walk the control flow graph from offset 0,
+// when the program has computed branches every immediate naming an instruction
+// is treated as a possible target
+fn reachable(v: &VerifiedProgram) -> Vec<bool> {
+    let ins = &v.instructions;
+    let mut roots = vec![0];
+    if ins
+        .iter()
+        .any(|d| matches!(d.instruction(), Some((I::Ja | I::CallA, _))))
+    {
+        roots.extend(ins.iter().filter_map(|d| match d.instruction() {
+            Some((I::Im8 | I::Im16 | I::Im32 | I::Im64 | I::Im8s | I::Im16s | I::Im32s, im)) => im,
+            _ => None,
+        }));
+    }
+    let mut reached = vec![false; ins.len()];
+    let mut work: Vec<usize> = roots.into_iter().filter_map(|o| v.index_of(o)).collect();
+    while let Some(i) = work.pop() {
+        if std::mem::replace(&mut reached[i], true) {
+            continue;
+        }
+        work.extend(
+            successors(&ins[i])
+                .into_iter()
+                .filter_map(|o| v.index_of(o)),
+        );
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn issues(prog: Vec<u8>) -> Vec<VerifyIssue> {
+        verify(&ProgramMemory::new(prog, vec![]))
+            .unwrap_err()
+            .issues
+    }
+
+    fn branch(ins: I, target: u64) -> Vec<u8> {
+        let mut code = vec![ins.opcode()];
+        code.extend(target.to_le_bytes());
+        code
+    }
+
+    #[test]
+    fn accepts_valid_programs() {
+        let program = assemble("top: Im8 1\n Jz end\n J top\n end: Ret").unwrap();
+        let verified = verify(&program).unwrap();
+        assert_eq!(verified.instructions().len(), 4);
+        assert!(verified.unreachable().is_empty());
+        assert_eq!(verified.index_of(20), Some(3));
+        assert_eq!(verified.index_of(3), None);
+        // a branch to the end of the program is allowed
+        assert!(verify(&ProgramMemory::new(branch(I::J, 9), vec![])).is_ok());
+        assert!(verify(&ProgramMemory::new(vec![], vec![])).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_bytes_and_targets() {
+        assert_eq!(
+            issues(vec![I::Nop.opcode(), 0xff]),
+            [VerifyIssue::InvalidOpcode {
+                offset: 1,
+                byte: 0xff
+            }]
+        );
+        assert_eq!(
+            issues(vec![I::Im16.opcode(), 1]),
+            [VerifyIssue::TruncatedImmediate {
+                offset: 0,
+                ins: I::Im16
+            }]
+        );
+        // into the middle of the instruction and past the end
+        let mut prog = branch(I::Jz, 1);
+        prog.extend(branch(I::Call, 19));
+        assert_eq!(
+            issues(prog),
+            [
+                VerifyIssue::BadBranchTarget {
+                    offset: 0,
+                    target: 1
+                },
+                VerifyIssue::BadBranchTarget {
+                    offset: 9,
+                    target: 19
+                },
+            ]
+        );
+        let e = verify(&ProgramMemory::new(vec![0xff, 0xfe], vec![])).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "program failed verification:\n  0x0: invalid opcode 0xff\n  0x1: invalid opcode 0xfe"
+        );
+    }
+
+    #[test]
+    fn reports_unreachable_instructions() {
+        let program = assemble("J end\n Nop\n Nop\n end: Ret\n Nop").unwrap();
+        assert_eq!(verify(&program).unwrap().unreachable(), [9, 10, 12]);
+        // immediates are possible targets of computed branches
+        let program = assemble("Im8 f\n Ja\n Nop\n f: Ret\n Nop").unwrap();
+        assert_eq!(verify(&program).unwrap().unreachable(), [3, 5]);
+    }
+}
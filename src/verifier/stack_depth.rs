@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::instruction::{Instructions as I, StackEffect};
+use crate::verifier::{successors, VerifiedProgram};
+
+// Stack depth dataflow over the control flow graph.
+//
+// Every function (offset 0 and each `Call` target) is analysed once with
+// depths relative to its entry. A callee may take values its caller pushed,
+// `needs` records how many; at a call site the callee summary is applied.
+// Computed branches (`Ja`, `CallA`), recursive calls and host calls without
+// a known effect end the path, their offsets are listed in `unanalyzed`.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackIssue {
+    // the calculation stack holds `depth` values but `needed` are popped
+    Underflow {
+        offset: u64,
+        needed: u64,
+        depth: u64,
+    },
+    // FromR or Ret with nothing pushed on the return stack by this function
+    ReturnUnderflow {
+        offset: u64,
+    },
+    // two paths reach the instruction with different (calculation, return) depths
+    InconsistentDepth {
+        offset: u64,
+        first: (i64, i64),
+        second: (i64, i64),
+    },
+    // Ret with values still on the return stack pushed by ToR
+    UnbalancedReturn {
+        offset: u64,
+        depth: i64,
+    },
+    // Rets of one function leave different calculation stack depths
+    InconsistentReturn {
+        entry: u64,
+        first: i64,
+        second: i64,
+    },
+}
+
+impl fmt::Display for StackIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Underflow {
+                offset,
+                needed,
+                depth,
+            } => write!(
+                f,
+                "{offset:#x}: calculation stack underflow, needs {needed} values but holds {depth}"
+            ),
+            Self::ReturnUnderflow { offset } => {
+                write!(f, "{offset:#x}: return stack underflow")
+            }
+            Self::InconsistentDepth {
+                offset,
+                first,
+                second,
+            } => write!(
+                f,
+                "{offset:#x}: inconsistent stack depths {first:?} and {second:?} at merge point"
+            ),
+            Self::UnbalancedReturn { offset, depth } => write!(
+                f,
+                "{offset:#x}: Ret with {depth} values left on the return stack"
+            ),
+            Self::InconsistentReturn {
+                entry,
+                first,
+                second,
+            } => write!(
+                f,
+                "function {entry:#x}: returns with stack effects {first} and {second}"
+            ),
+        }
+    }
+}
+
+// stack behaviour of a function relative to its entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionSummary {
+    pub entry: u64,
+    // values taken from the caller's calculation stack
+    pub needs: u64,
+    // calculation stack depth change at Ret, None when it never returns
+    pub net: Option<i64>,
+    // maximum calculation and return stack depth above the entry depth,
+    // the return depth includes the return address
+    pub max_depth: i64,
+    pub max_return_depth: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackReport {
+    // maximum depths reached on analysed paths from offset 0
+    pub max_depth: u64,
+    pub max_return_depth: u64,
+    pub issues: Vec<StackIssue>,
+    pub functions: Vec<FunctionSummary>,
+    pub unanalyzed: Vec<u64>,
+}
+
+// `host_effects` gives the stack effect of HostCall ids
+pub fn analyze_stack_depth(
+    program: &VerifiedProgram,
+    host_effects: &HashMap<u16, StackEffect>,
+) -> StackReport {
+    let mut a = Analyzer {
+        program,
+        host_effects,
+        summaries: HashMap::new(),
+        issues: Vec::new(),
+        unanalyzed: Vec::new(),
+    };
+    let main = match program.instructions().is_empty() {
+        true => None,
+        false => Some(a.function(0, true)),
+    };
+    let mut functions: Vec<FunctionSummary> = a.summaries.into_values().flatten().collect();
+    functions.sort_by_key(|f| f.entry);
+    a.issues.sort_by_key(issue_offset);
+    a.unanalyzed.sort();
+    a.unanalyzed.dedup();
+    StackReport {
+        max_depth: main.as_ref().map_or(0, |m| m.max_depth as u64),
+        max_return_depth: main.as_ref().map_or(0, |m| m.max_return_depth as u64),
+        issues: a.issues,
+        functions,
+        unanalyzed: a.unanalyzed,
+    }
+}
+
+fn issue_offset(i: &StackIssue) -> u64 {
+    match i {
+        StackIssue::Underflow { offset, .. }
+        | StackIssue::ReturnUnderflow { offset }
+        | StackIssue::InconsistentDepth { offset, .. }
+        | StackIssue::UnbalancedReturn { offset, .. } => *offset,
+        StackIssue::InconsistentReturn { entry, .. } => *entry,
+    }
+}
+
+struct Analyzer<'a, 'p> {
+    program: &'a VerifiedProgram<'p>,
+    host_effects: &'a HashMap<u16, StackEffect>,
+    // None while the function is being analysed
+    summaries: HashMap<u64, Option<FunctionSummary>>,
+    issues: Vec<StackIssue>,
+    unanalyzed: Vec<u64>,
+}
+
+impl Analyzer<'_, '_> {
+    // `main` is the program entry, nothing lies below its stacks
+    fn function(&mut self, entry: u64, main: bool) -> FunctionSummary {
+        self.summaries.insert(entry, None);
+        let ins = self.program.instructions();
+        let mut summary = FunctionSummary {
+            entry,
+            needs: 0,
+            net: None,
+            max_depth: 0,
+            max_return_depth: 0,
+        };
+        // (calculation, return) depth before each instruction
+        let mut states: HashMap<usize, (i64, i64)> = HashMap::new();
+        let mut work = Vec::new();
+        // a callee starts with its return address on the return stack
+        let r_entry = if main { 0 } else { 1 };
+        summary.max_return_depth = r_entry;
+        if let Some(i) = self.program.index_of(entry) {
+            states.insert(i, (0, r_entry));
+            work.push(i);
+        }
+        while let Some(i) = work.pop() {
+            let d = &ins[i];
+            let (depth, r_depth) = states[&i];
+            let Some((op, im)) = d.instruction() else {
+                continue;
+            };
+            // (pops, pushes, return pops, return pushes)
+            let (pops, pushes, r_pops, r_pushes) = match op {
+                I::HostCall => match self.host_effects.get(&(im.unwrap_or(0) as u16)) {
+                    Some(e) => widen(*e),
+                    None => {
+                        self.unanalyzed.push(d.offset);
+                        continue;
+                    }
+                },
+                I::Call => {
+                    let Some(callee) = self.callee(im.unwrap_or(0)) else {
+                        self.unanalyzed.push(d.offset);
+                        continue;
+                    };
+                    let Some(net) = callee.net else {
+                        continue;
+                    };
+                    summary.max_depth = summary.max_depth.max(depth + callee.max_depth);
+                    summary.max_return_depth = summary
+                        .max_return_depth
+                        .max(r_depth + callee.max_return_depth);
+                    // the return address is pushed and popped again by the callee
+                    let needs = callee.needs as i64;
+                    (needs, needs + net, 0, 0)
+                }
+                _ => widen(op.stack_effect()),
+            };
+            let after = depth - pops;
+            if after < 0 {
+                if main {
+                    self.issues.push(StackIssue::Underflow {
+                        offset: d.offset,
+                        needed: pops as u64,
+                        depth: depth as u64,
+                    });
+                    continue;
+                }
+                summary.needs = summary.needs.max(after.unsigned_abs());
+            }
+            let r_after = r_depth - r_pops;
+            if r_after < 0 {
+                self.issues
+                    .push(StackIssue::ReturnUnderflow { offset: d.offset });
+                continue;
+            }
+            let depth = after + pushes;
+            let r_depth = r_after + r_pushes;
+            summary.max_depth = summary.max_depth.max(depth);
+            summary.max_return_depth = summary.max_return_depth.max(r_depth);
+
+            let next = match op {
+                I::Ja | I::CallA => {
+                    self.unanalyzed.push(d.offset);
+                    continue;
+                }
+                // in the program entry Ret jumps to a value pushed by ToR
+                I::Ret if main => {
+                    self.unanalyzed.push(d.offset);
+                    continue;
+                }
+                I::Ret => {
+                    if r_after > 0 {
+                        self.issues.push(StackIssue::UnbalancedReturn {
+                            offset: d.offset,
+                            depth: r_after,
+                        });
+                    } else {
+                        self.returns(&mut summary, depth);
+                    }
+                    continue;
+                }
+                I::Call => vec![d.next_offset()],
+                _ => successors(d),
+            };
+            for o in next {
+                // falling off the end stops the program
+                let Some(j) = self.program.index_of(o) else {
+                    continue;
+                };
+                match states.get(&j) {
+                    None => {
+                        states.insert(j, (depth, r_depth));
+                        work.push(j);
+                    }
+                    Some(&s) if s != (depth, r_depth) => {
+                        self.issues.push(StackIssue::InconsistentDepth {
+                            offset: o,
+                            first: s,
+                            second: (depth, r_depth),
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        self.summaries.insert(entry, Some(summary.clone()));
+        summary
+    }
+    fn returns(&mut self, summary: &mut FunctionSummary, depth: i64) {
+        match summary.net {
+            None => summary.net = Some(depth),
+            Some(first) if first != depth => self.issues.push(StackIssue::InconsistentReturn {
+                entry: summary.entry,
+                first,
+                second: depth,
+            }),
+            Some(_) => {}
+        }
+    }
+    // summary of a call target, None while it is still being analysed (recursion)
+    fn callee(&mut self, target: u64) -> Option<FunctionSummary> {
+        match self.summaries.get(&target) {
+            Some(s) => s.clone(),
+            None => Some(self.function(target, false)),
+        }
+    }
+}
+
+fn widen(e: StackEffect) -> (i64, i64, i64, i64) {
+    (
+        e.pops as i64,
+        e.pushes as i64,
+        e.r_pops as i64,
+        e.r_pushes as i64,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::verifier::verify;
+
+    fn report(src: &str) -> StackReport {
+        report_with(src, &HashMap::new())
+    }
+
+    fn report_with(src: &str, host_effects: &HashMap<u16, StackEffect>) -> StackReport {
+        let program = assemble(src).unwrap();
+        analyze_stack_depth(&verify(&program).unwrap(), host_effects)
+    }
+
+    #[test]
+    fn straight_line_depths() {
+        let r = report("Im8 1\n Im8 2\n Im8 3\n Add\n ToR\n Dup\n FromR");
+        assert!(r.issues.is_empty());
+        assert_eq!((r.max_depth, r.max_return_depth), (3, 1));
+        // the program entry is summarised too
+        assert_eq!(r.functions.len(), 1);
+        assert_eq!(r.functions[0].entry, 0);
+        assert_eq!(report("").max_depth, 0);
+    }
+
+    #[test]
+    fn underflows() {
+        let r = report("Im8 1\n Add\n FromR");
+        assert_eq!(
+            r.issues,
+            [StackIssue::Underflow {
+                offset: 2,
+                needed: 2,
+                depth: 1
+            },]
+        );
+        let r = report("FromR");
+        assert_eq!(r.issues, [StackIssue::ReturnUnderflow { offset: 0 }]);
+    }
+
+    #[test]
+    fn merge_points_need_equal_depths() {
+        let r = report("Im8 0\n Jz skip\n Im8 1\n skip: Nop");
+        assert_eq!(
+            r.issues,
+            [StackIssue::InconsistentDepth {
+                offset: 13,
+                first: (0, 0),
+                second: (1, 0)
+            }]
+        );
+        // a balanced loop
+        let r = report("Im8 3\n top: Im8 1\n Sub\n Dup\n Jnz top");
+        assert!(r.issues.is_empty());
+        assert_eq!(r.max_depth, 2);
+    }
+
+    #[test]
+    fn summarises_functions_at_calls() {
+        let r = report(
+            "
+            Im8 2
+            Im8 3
+            Call add
+            Call add
+            J end
+        add: Add
+            Ret
+        end: Nop",
+        );
+        assert_eq!(
+            r.issues,
+            [StackIssue::Underflow {
+                offset: 13,
+                needed: 2,
+                depth: 1
+            }]
+        );
+        let add = &r.functions[1];
+        assert_eq!((add.entry, add.needs, add.net), (31, 2, Some(-1)));
+        assert_eq!((add.max_depth, add.max_return_depth), (0, 1));
+        assert_eq!(r.max_return_depth, 1);
+
+        let r = report(
+            "
+            Call f
+            J end
+        f: Im8 0
+            Jz a
+            Im8 1
+            Ret
+        a: Ret
+        end: Nop",
+        );
+        assert_eq!(
+            r.issues,
+            [StackIssue::InconsistentReturn {
+                entry: 18,
+                first: 0,
+                second: 1
+            }]
+        );
+        let r = report("Call f\n f: Im8 1\n ToR\n Ret");
+        assert_eq!(
+            r.issues,
+            [StackIssue::UnbalancedReturn {
+                offset: 12,
+                depth: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn lists_unanalyzed_instructions() {
+        assert_eq!(report("Im8 5\n CallA").unanalyzed, [2]);
+        assert_eq!(report("Im8 5\n Ja").unanalyzed, [2]);
+        // unknown host calls end the path
+        assert_eq!(report("HostCall 1\n Ret").unanalyzed, [0]);
+        // so do recursive calls, a function that never returns ends its callers
+        let r = report("Call f\n Ret\n f: Call f\n Ret");
+        assert_eq!(r.unanalyzed, [10]);
+        // the entry's Ret jumps to a ToR value
+        assert_eq!(report("Im8 0\n ToR\n Ret").unanalyzed, [3]);
+        let effects = HashMap::from([(1, StackEffect::new(0, 2))]);
+        let r = report_with("HostCall 1\n Add", &effects);
+        assert!(r.unanalyzed.is_empty());
+        assert_eq!(r.max_depth, 2);
+        assert!(r.issues.is_empty());
+    }
+}